@@ -1,12 +1,36 @@
 use actix_web::{middleware, web, App, HttpServer};
 use actix_files as fs;
 use actix_cors::Cors;
-use lib::server::broadcaster;
-use log::info;
-use std::{env, sync::Arc};
+use lib::server::{audit::AuditLog, broadcaster::{self, BroadcasterConfiguration}, metrics::Metrics, oidc::{Oidc, OidcConfiguration}, roles::Roles, sessions::Sessions, tokens::TokenStore, users::{self, Users}};
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use std::{env, io, sync::Arc};
+
+/// Parses the JSON file the environment variable `name` points to, the default when it isn't set.
+fn load_from_env<T: Default + DeserializeOwned>(name: &str) -> io::Result<T> {
+    let Ok(path) = env::var(name) else {
+        return Ok(T::default());
+    };
+    let loaded = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Error reading {} {}: {}", name, path, e))
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| format!("Error parsing {} {}: {}", name, path, e)));
+    loaded.map_err(|e| {
+        error!("{}", e);
+        io::Error::other(e)
+    })
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // `server hash-password` reads a password from stdin and prints its hash for the users file
+    if env::args().nth(1).as_deref() == Some("hash-password") {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        let hash = users::hash_password(password.trim_end_matches(['\r', '\n'])).map_err(io::Error::other)?;
+        println!("{}", hash);
+        return Ok(());
+    }
+
     let environment = env::var("ENVIRONMENT").unwrap_or_else(|_| "dev".to_string());
     if environment == "dev" {
         dotenv::dotenv().ok();
@@ -24,8 +48,65 @@ async fn main() -> std::io::Result<()> {
     info!("starting at http://{}:{}", host, port);
     info!("frontend origin: {}", frontend_origin);
 
-    let broadcasters = broadcaster::new_broadcasters();
+    let history_lines = env::var("HISTORY_LINES").ok().and_then(|lines| lines.parse().ok()).unwrap_or(1000);
+    let history_bytes = env::var("HISTORY_BYTES").ok().and_then(|bytes| bytes.parse().ok()).unwrap_or(2_usize.pow(20));
+    info!("keeping up to {} lines / {} bytes of history per application", history_lines, history_bytes);
+    let broadcast_capacity = env::var("BROADCAST_CAPACITY").ok().and_then(|capacity| capacity.parse().ok()).unwrap_or(100);
+    info!("subscribers may fall {} messages behind before skipping some", broadcast_capacity);
+
+    let broadcasters = broadcaster::new_broadcasters(BroadcasterConfiguration::new(history_lines, history_bytes, broadcast_capacity));
     let broadcasters = Arc::new(broadcasters);
+    let metrics = Arc::new(Metrics::default());
+    let tokens: TokenStore = load_from_env("CLIENT_TOKENS_FILE")?;
+    match tokens.is_enabled() {
+        true => info!("clients need a token of CLIENT_TOKENS_FILE to publish"),
+        false => warn!("CLIENT_TOKENS_FILE is not set, any client may publish any application"),
+    }
+    let tokens = Arc::new(tokens);
+    let users: Users = load_from_env("USERS_FILE")?;
+    let oidc = OidcConfiguration::from_env().map_err(|e| {
+        error!("{}", e);
+        io::Error::other(e)
+    })?;
+    if let Some(oidc) = &oidc {
+        info!("users may log in with OIDC provider {}", oidc.issuer());
+    }
+    match (users.is_enabled(), &oidc) {
+        (true, _) => info!("users of USERS_FILE may log in to the UI"),
+        (false, Some(_)) => {},
+        (false, None) => warn!("Neither USERS_FILE nor OIDC_ISSUER is set, the UI and its API are open to anyone"),
+    }
+    let users = Arc::new(users);
+    let oidc = Arc::new(Oidc::new(oidc));
+    let roles: Roles = load_from_env("ROLES_FILE")?;
+    match (roles.is_enabled(), users.is_enabled() || oidc.is_enabled()) {
+        (true, true) => info!("the roles of ROLES_FILE decide which applications users may view"),
+        (true, false) => warn!("ROLES_FILE is set without USERS_FILE or OIDC_ISSUER, without anyone to log in no application is visible"),
+        (false, true) => warn!("ROLES_FILE is not set, every user may view every application"),
+        (false, false) => {},
+    }
+    let roles = Arc::new(roles);
+    let session_ttl = env::var("SESSION_TTL_SECS").ok().and_then(|secs| secs.parse().ok()).unwrap_or(8 * 60 * 60);
+    let secure_cookie = env::var("SESSION_COOKIE_SECURE").map(|secure| secure == "true").unwrap_or(false);
+    info!("login sessions expire after {} seconds", session_ttl);
+    let admin_role = env::var("AUDIT_ADMIN_ROLE").unwrap_or_else(|_| "admin".to_string());
+    let audit = match env::var("AUDIT_LOG_FILE") {
+        Ok(path) => {
+            let max_bytes = env::var("AUDIT_LOG_MAX_BYTES").ok().and_then(|bytes| bytes.parse().ok()).unwrap_or(10 * 2_u64.pow(20));
+            let keep = env::var("AUDIT_LOG_FILES").ok().and_then(|files| files.parse().ok()).unwrap_or(5);
+            info!("auditing to {}, rotated at {} bytes keeping {} files, queried by role {}", path, max_bytes, keep, admin_role);
+            AuditLog::open(&path, max_bytes, keep, admin_role).map_err(|e| {
+                error!("Error opening audit log {}: {}", path, e);
+                e
+            })?
+        },
+        Err(_) => {
+            warn!("AUDIT_LOG_FILE is not set, nothing is audited");
+            AuditLog::disabled(admin_role)
+        },
+    };
+    let audit = Arc::new(audit);
+    let sessions = Arc::new(Sessions::new(chrono::Duration::seconds(session_ttl), secure_cookie));
 
     HttpServer::new(move || {
         // CORS configuration for development
@@ -36,19 +117,26 @@ async fn main() -> std::io::Result<()> {
             .supports_credentials();
         let broadcasters = Arc::clone(&broadcasters);
         let broadcasters = web::Data::new(broadcasters);
+        let metrics = web::Data::new(Arc::clone(&metrics));
+        let tokens = web::Data::new(Arc::clone(&tokens));
+        let users = web::Data::new(Arc::clone(&users));
+        let roles = web::Data::new(Arc::clone(&roles));
+        let oidc = web::Data::new(Arc::clone(&oidc));
+        let audit = web::Data::new(Arc::clone(&audit));
+        let sessions = web::Data::new(Arc::clone(&sessions));
 
         App::new()
             .wrap(middleware::Logger::default())
             .wrap(cors)
             .app_data(broadcasters)
-            // API routes
-            .service(lib::server::controller::outbound::hello)
-            // WebSocket route
-            .service(lib::server::controller::inbound::data_inbound_ws)
-            // SSE route
-            .service(lib::server::controller::outbound::data_outbound_sse)
-            // API route to get the current registered applications
-            .service(lib::server::controller::outbound::current_registered_applications)
+            .app_data(metrics)
+            .app_data(tokens)
+            .app_data(users)
+            .app_data(roles)
+            .app_data(oidc)
+            .app_data(audit)
+            .app_data(sessions)
+            .configure(lib::server::controller::routes)
             // In production, serve the built frontend
             .service(
                 fs::Files::new("/", &path_to_front_end)
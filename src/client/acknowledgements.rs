@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+
+use log::warn;
+
+use crate::message::FileOffset;
+
+/// Frames kept for a server that fails to acknowledge them, past this the oldest are given up on.
+const MAX_UNACKNOWLEDGED: usize = 10_000;
+
+struct Frame {
+    sequence: u64,
+    message: Vec<u8>,
+    file_offsets: Vec<FileOffset>
+}
+
+/// Frames sent to a server that agreed to acknowledge them, kept until it does so they can be sent again after a reconnect.
+/// Sequence numbers are only unique together with the client id, which changes every time the client starts.
+pub struct Unacknowledged {
+    client_id: String,
+    last_sequence: u64,
+    frames: VecDeque<Frame>
+}
+
+impl Default for Unacknowledged {
+    fn default() -> Self {
+        let client_id = format!("{:x}-{:x}", std::process::id(), chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default());
+        Self { client_id, last_sequence: 0, frames: VecDeque::new() }
+    }
+}
+
+impl Unacknowledged {
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Keeps a serialized message until it's acknowledged and returns the sequence number to send it with.
+    pub fn push(&mut self, message: Vec<u8>, file_offsets: Vec<FileOffset>) -> u64 {
+        self.last_sequence += 1;
+        self.frames.push_back(Frame { sequence: self.last_sequence, message, file_offsets });
+        if self.frames.len() > MAX_UNACKNOWLEDGED {
+            warn!("More than {} frames unacknowledged, giving up on the oldest", MAX_UNACKNOWLEDGED);
+            self.frames.pop_front();
+        }
+        self.last_sequence
+    }
+
+    /// Forgets every frame up to and including `sequence`, returning the file offsets they got to.
+    pub fn acknowledge(&mut self, sequence: u64) -> Vec<FileOffset> {
+        let mut file_offsets = vec![];
+        while self.frames.front().is_some_and(|frame| frame.sequence <= sequence) {
+            if let Some(frame) = self.frames.pop_front() {
+                file_offsets.extend(frame.file_offsets);
+            }
+        }
+        file_offsets
+    }
+
+    /// Frames to send again, in the order they were first sent.
+    pub fn frames(&self) -> Vec<(u64, Vec<u8>)> {
+        self.frames.iter().map(|frame| (frame.sequence, frame.message.clone())).collect()
+    }
+
+    /// Empties the queue for a server that no longer acknowledges, returning what was in it.
+    pub fn take(&mut self) -> Vec<(Vec<u8>, Vec<FileOffset>)> {
+        self.frames.drain(..).map(|frame| (frame.message, frame.file_offsets)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::message::FileIdentity;
+
+    fn offset(offset: u64) -> FileOffset {
+        FileOffset { path: PathBuf::from("app.log"), identity: FileIdentity::default(), offset }
+    }
+
+    #[test]
+    fn acknowledges_cumulatively() {
+        let mut unacknowledged = Unacknowledged::default();
+        assert_eq!(unacknowledged.push(vec![1], vec![offset(10)]), 1);
+        assert_eq!(unacknowledged.push(vec![2], vec![offset(20), offset(30)]), 2);
+        assert_eq!(unacknowledged.push(vec![3], vec![]), 3);
+
+        assert_eq!(unacknowledged.acknowledge(2), vec![offset(10), offset(20), offset(30)]);
+        assert_eq!(unacknowledged.frames(), vec![(3, vec![3])]);
+        assert_eq!(unacknowledged.acknowledge(2), vec![]);
+    }
+
+    #[test]
+    fn keeps_sequence_numbers_after_being_emptied() {
+        let mut unacknowledged = Unacknowledged::default();
+        unacknowledged.push(vec![1], vec![offset(10)]);
+        assert_eq!(unacknowledged.take(), vec![(vec![1], vec![offset(10)])]);
+        assert!(unacknowledged.frames().is_empty());
+        assert_eq!(unacknowledged.push(vec![2], vec![]), 2);
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How long to wait between attempts to connect to the server.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct Reconnect {
+    /// Wait after the first failed attempt, doubled with every further one
+    initial_delay_ms: u64,
+    /// Longest wait between attempts
+    max_delay_ms: u64,
+    /// A connection that lasted this long counts as stable, the next wait starts over from the initial one
+    stable_after_secs: u64
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self { initial_delay_ms: 1000, max_delay_ms: 60_000, stable_after_secs: 60 }
+    }
+}
+
+impl Reconnect {
+    pub fn stable_after(&self) -> Duration {
+        Duration::from_secs(self.stable_after_secs)
+    }
+}
+
+/// Exponential backoff with jitter, so clients that lost the server together don't all come back at once.
+pub struct Backoff {
+    reconnect: Reconnect,
+    attempt: u32
+}
+
+impl Backoff {
+    pub fn new(reconnect: Reconnect) -> Self {
+        Self { reconnect, attempt: 0 }
+    }
+
+    /// Attempts since the last reset.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Wait before the next attempt, somewhere between half of and the full exponential delay.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.reconnect.initial_delay_ms
+            .saturating_mul(2_u64.saturating_pow(self.attempt))
+            .min(self.reconnect.max_delay_ms);
+        self.attempt = self.attempt.saturating_add(1);
+        Duration::from_millis(rand::thread_rng().gen_range(delay / 2..=delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> Backoff {
+        Backoff::new(Reconnect { initial_delay_ms: 100, max_delay_ms: 1000, stable_after_secs: 60 })
+    }
+
+    #[test]
+    fn doubles_up_to_the_max_delay() {
+        let mut backoff = backoff();
+        for max in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay().as_millis() as u64;
+            assert!(delay >= max / 2 && delay <= max, "{} not within {}", delay, max);
+        }
+        assert_eq!(backoff.attempt(), 6);
+    }
+
+    #[test]
+    fn starts_over_after_a_reset() {
+        let mut backoff = backoff();
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::message::{BatchedDataMessage, BinaryMessage, DataMessage, FileOffset};
+
+/// Data messages waiting to go out in one frame, flushed once full or once the first of them waited long enough.
+pub struct Batch {
+    messages: Vec<BatchedDataMessage>,
+    offsets: Vec<FileOffset>,
+    bytes: usize,
+    max_bytes: usize,
+    linger: Duration,
+    deadline: Option<Instant>
+}
+
+impl Batch {
+    pub fn new(max_bytes: usize, linger: Duration) -> Self {
+        Self { messages: vec![], offsets: vec![], bytes: 0, max_bytes, linger, deadline: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Whether `data` would push the batch over its size, in which case it should be flushed first.
+    pub fn overflows(&self, data: &DataMessage) -> bool {
+        !self.is_empty() && self.bytes + data.batched_size() > self.max_bytes
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.bytes >= self.max_bytes
+    }
+
+    /// When the batch has to be flushed at the latest, if there's anything in it.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn push(&mut self, data: DataMessage) {
+        if let Some(file_offset) = data.file_offset() {
+            self.offsets.push(file_offset.clone());
+        }
+        self.bytes += data.batched_size();
+        self.messages.push(BatchedDataMessage::from(data));
+        self.deadline.get_or_insert_with(|| Instant::now() + self.linger);
+    }
+
+    /// Empties the batch into a message, along with the file offsets to commit once it's handled.
+    pub fn take(&mut self) -> (BinaryMessage, Vec<FileOffset>) {
+        self.bytes = 0;
+        self.deadline = None;
+        (BinaryMessage::Batch(std::mem::take(&mut self.messages)), std::mem::take(&mut self.offsets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{message::{FileIdentity, Message}, Applicatiton};
+
+    fn data(row: &str, offset: u64) -> DataMessage {
+        let file_offset = FileOffset { path: PathBuf::from("app.log"), identity: FileIdentity::default(), offset };
+        DataMessage::new(row.to_string(), Applicatiton::SinglePod("app".to_string()), false)
+            .with_file_name("app.log".to_string())
+            .with_file_offset(file_offset)
+    }
+
+    #[test]
+    fn fills_up_to_the_max_bytes() {
+        let line = data("0123456789", 11);
+        let mut batch = Batch::new(line.batched_size() * 2, Duration::from_millis(20));
+        // Even a line over the size goes into an empty batch
+        assert!(!batch.overflows(&data(&"x".repeat(1000), 1001)));
+        assert!(batch.deadline().is_none());
+
+        batch.push(line.clone());
+        assert!(batch.deadline().is_some());
+        assert!(!batch.overflows(&line));
+        assert!(!batch.is_full());
+        batch.push(line.clone());
+        assert!(batch.overflows(&line));
+        assert!(batch.is_full());
+    }
+
+    #[test]
+    fn takes_the_lines_with_their_offsets() {
+        let mut batch = Batch::new(1024, Duration::from_millis(20));
+        batch.push(data("first", 6));
+        batch.push(data("second", 13));
+        let (message, offsets) = batch.take();
+        assert!(batch.is_empty() && batch.deadline().is_none());
+        assert_eq!(offsets.iter().map(|offset| offset.offset).collect::<Vec<_>>(), [6, 13]);
+
+        let application = Applicatiton::SinglePod("app".to_string());
+        let bytes = borsh::to_vec(&message).unwrap();
+        let messages = borsh::from_slice::<BinaryMessage>(&bytes).unwrap().into_messages(&application).unwrap();
+        let rows: Vec<&str> = messages.iter().filter_map(Message::data).map(DataMessage::row).collect();
+        assert_eq!(rows, ["first", "second"]);
+        assert!(messages.iter().filter_map(Message::data).all(|data| serde_json::to_value(data).unwrap()["file_name"] == "app.log"));
+    }
+}
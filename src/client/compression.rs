@@ -0,0 +1,95 @@
+use log::error;
+
+use crate::message::{compression::{self, COMPRESSION_THRESHOLD}, BinaryMessage};
+
+/// Compresses outgoing messages when the server agreed to it, keeping count of how much it saved.
+pub struct Compressor {
+    enabled: bool,
+    uncompressed_bytes: u64,
+    sent_bytes: u64
+}
+
+impl Compressor {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, uncompressed_bytes: 0, sent_bytes: 0 }
+    }
+
+    /// Bytes to send for a serialized message, compressed when that's on and worth it.
+    pub fn encode(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        self.uncompressed_bytes += bytes.len() as u64;
+        let bytes = match self.enabled && bytes.len() >= COMPRESSION_THRESHOLD {
+            true => match compression::compress(&bytes).and_then(|compressed| borsh::to_vec(&BinaryMessage::Compressed(compressed))) {
+                Ok(compressed) if compressed.len() < bytes.len() => compressed,
+                Ok(_) => bytes,
+                Err(e) => {
+                    error!("Failed to compress message, sending it as it is: {}", e);
+                    bytes
+                }
+            },
+            false => bytes,
+        };
+        self.sent_bytes += bytes.len() as u64;
+        bytes
+    }
+
+    /// Bytes before compression / bytes sent.
+    pub fn ratio(&self) -> f64 {
+        match self.sent_bytes {
+            0 => 1.0,
+            sent_bytes => self.uncompressed_bytes as f64 / sent_bytes as f64,
+        }
+    }
+
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes
+    }
+
+    pub fn uncompressed_bytes(&self) -> u64 {
+        self.uncompressed_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message::{DataMessage, Message}, Applicatiton};
+
+    fn message(row: &str) -> Vec<u8> {
+        let data = DataMessage::new(row.to_string(), Applicatiton::SinglePod("app".to_string()), false);
+        borsh::to_vec(&BinaryMessage::from(Message::Data(data))).unwrap()
+    }
+
+    #[test]
+    fn sends_small_messages_as_they_are() {
+        let mut compressor = Compressor::new(true);
+        let bytes = message("short");
+        assert!(bytes.len() < COMPRESSION_THRESHOLD);
+        assert_eq!(compressor.encode(bytes.clone()), bytes);
+        assert_eq!(compressor.ratio(), 1.0);
+    }
+
+    #[test]
+    fn sends_messages_as_they_are_when_disabled() {
+        let mut compressor = Compressor::new(false);
+        let bytes = message(&"repeated ".repeat(100));
+        assert_eq!(compressor.encode(bytes.clone()), bytes);
+        assert_eq!(compressor.sent_bytes(), compressor.uncompressed_bytes());
+    }
+
+    #[test]
+    fn compresses_large_messages() {
+        let mut compressor = Compressor::new(true);
+        let row = "repeated ".repeat(100);
+        let bytes = message(&row);
+        let encoded = compressor.encode(bytes.clone());
+        assert!(encoded.len() < bytes.len());
+        assert_eq!(compressor.uncompressed_bytes(), bytes.len() as u64);
+        assert_eq!(compressor.sent_bytes(), encoded.len() as u64);
+        assert!(compressor.ratio() > 1.0);
+
+        let application = Applicatiton::SinglePod("app".to_string());
+        let messages = borsh::from_slice::<BinaryMessage>(&encoded).unwrap().into_messages(&application).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].data().map(DataMessage::row), Some(row.as_str()));
+    }
+}
@@ -1,7 +1,9 @@
 use log::error;
 use serde::{Deserialize, Serialize};
 
-use crate::Applicatiton;
+use crate::{message::handshake::PausePolicy, Applicatiton};
+
+use super::{backoff::Reconnect, file_selection::FileSelection, file_watcher::FileWatcherBackend, history::LineTimestamp, line_assembler::InvalidUtf8, start_position::StartFrom, tls::Tls};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LogConfiguration {
@@ -12,7 +14,63 @@ pub struct LogConfiguration {
     server_host: String,
     server_port: i16,
     server_path: String,
-    channel_buffer: usize
+    channel_buffer: usize,
+    #[serde(default)]
+    spool_file: Option<String>,
+    #[serde(default = "default_max_catch_up_bytes")]
+    max_catch_up_bytes: u64,
+    #[serde(default)]
+    invalid_utf8: InvalidUtf8,
+    #[serde(default = "default_max_line_length")]
+    max_line_length: usize,
+    #[serde(default)]
+    file_selection: FileSelection,
+    #[serde(default)]
+    tail_mode: TailMode,
+    #[serde(default)]
+    file_watcher: FileWatcherBackend,
+    #[serde(default)]
+    start_from: StartFrom,
+    #[serde(default)]
+    line_timestamp: Option<LineTimestamp>,
+    #[serde(default = "default_batch_max_bytes")]
+    batch_max_bytes: usize,
+    #[serde(default = "default_batch_linger_ms")]
+    batch_linger_ms: u64,
+    #[serde(default)]
+    pause_policy: PausePolicy,
+    #[serde(default)]
+    reconnect: Reconnect,
+    #[serde(default)]
+    tls: Option<Tls>,
+    #[serde(default)]
+    token: Option<String>
+}
+
+/// Whether a config follows one file or every file matching its regex.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TailMode {
+    /// One file, chosen by the file selection
+    #[default]
+    Single,
+    /// Every matching file, including the ones created later
+    AllMatching
+}
+
+fn default_max_catch_up_bytes() -> u64 {
+    2_u64.pow(20)
+}
+
+fn default_max_line_length() -> usize {
+    2_usize.pow(16)
+}
+
+fn default_batch_max_bytes() -> usize {
+    2_usize.pow(15)
+}
+
+fn default_batch_linger_ms() -> u64 {
+    20
 }
 
 impl LogConfiguration {
@@ -43,6 +101,66 @@ impl LogConfiguration {
     pub fn get_channel_buffer(&self) -> usize {
         self.channel_buffer
     }
+
+    pub fn get_spool_file(&self) -> Option<String> {
+        self.spool_file.clone()
+    }
+
+    pub fn get_max_catch_up_bytes(&self) -> u64 {
+        self.max_catch_up_bytes
+    }
+
+    pub fn get_invalid_utf8(&self) -> InvalidUtf8 {
+        self.invalid_utf8
+    }
+
+    pub fn get_max_line_length(&self) -> usize {
+        self.max_line_length
+    }
+
+    pub fn get_file_selection(&self) -> FileSelection {
+        self.file_selection.clone()
+    }
+
+    pub fn get_tail_mode(&self) -> TailMode {
+        self.tail_mode
+    }
+
+    pub fn get_file_watcher(&self) -> FileWatcherBackend {
+        self.file_watcher
+    }
+
+    pub fn get_start_from(&self) -> StartFrom {
+        self.start_from
+    }
+
+    pub fn get_line_timestamp(&self) -> Option<LineTimestamp> {
+        self.line_timestamp.clone()
+    }
+
+    pub fn get_batch_max_bytes(&self) -> usize {
+        self.batch_max_bytes
+    }
+
+    pub fn get_batch_linger_ms(&self) -> u64 {
+        self.batch_linger_ms
+    }
+
+    pub fn get_pause_policy(&self) -> PausePolicy {
+        self.pause_policy
+    }
+
+    pub fn get_reconnect(&self) -> Reconnect {
+        self.reconnect
+    }
+
+    pub fn get_tls(&self) -> Option<Tls> {
+        self.tls.clone()
+    }
+
+    pub fn get_token(&self) -> Option<String> {
+        self.token.clone()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
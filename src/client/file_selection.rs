@@ -0,0 +1,154 @@
+use std::{fs, path::PathBuf, time::SystemTime};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use log::error;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Which file to tail when several in the directory match the file name regex.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub enum FileSelection {
+    /// First match in directory order, which is arbitrary
+    #[default]
+    FirstMatch,
+    /// Most recently modified match
+    NewestModified,
+    /// Lexicographically greatest file name
+    GreatestName,
+    /// Latest timestamp captured from the file name by the `timestamp` group of the regex,
+    /// or its first group when there's no such group, parsed with the chrono `format`
+    CapturedTimestamp { format: String }
+}
+
+struct Candidate {
+    path: PathBuf,
+    file_name: String,
+    modified: Option<SystemTime>
+}
+
+impl FileSelection {
+    /// Whether a newer file may come up later, which makes it worth rescanning while a file is tailed.
+    pub fn prefers_newer(&self) -> bool {
+        *self != FileSelection::FirstMatch
+    }
+
+    /// Picks the file to tail out of the files in `dir` whose names match `regex`.
+    pub fn select(&self, dir: &str, regex: &str) -> Option<PathBuf> {
+        let regex = compile(regex)?;
+        let mut candidates = candidates(dir, &regex).into_iter();
+
+        let selected = match self {
+            FileSelection::FirstMatch => candidates.next(),
+            FileSelection::NewestModified => candidates.max_by_key(|candidate| candidate.modified),
+            FileSelection::GreatestName => candidates.max_by(|a, b| a.file_name.cmp(&b.file_name)),
+            FileSelection::CapturedTimestamp { format } => candidates
+                .filter_map(|candidate| captured_timestamp(&regex, &candidate.file_name, format).map(|timestamp| (timestamp, candidate)))
+                .max_by_key(|(timestamp, _)| *timestamp)
+                .map(|(_, candidate)| candidate),
+        };
+        selected.map(|candidate| candidate.path)
+    }
+}
+
+/// All the files in `dir` whose names match `regex`.
+pub fn matching_files(dir: &str, regex: &str) -> Vec<PathBuf> {
+    match compile(regex) {
+        Some(regex) => candidates(dir, &regex).into_iter().map(|candidate| candidate.path).collect(),
+        None => vec![],
+    }
+}
+
+fn compile(regex: &str) -> Option<Regex> {
+    match Regex::new(regex) {
+        Ok(regex) => Some(regex),
+        Err(e) => {
+            error!("Error creating regex: {}", e);
+            None
+        }
+    }
+}
+
+fn candidates(dir: &str, regex: &Regex) -> Vec<Candidate> {
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) => {
+            error!("Error reading directory: {}", e);
+            return vec![]
+        }
+    };
+
+    files.filter_map(|file| {
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Error reading file: {}", e);
+                return None
+            }
+        };
+        let file_name = match file.file_name().into_string() {
+            Ok(file_name) => file_name,
+            Err(e) => {
+                error!("Error reading file name: {:?}", e);
+                return None
+            }
+        };
+        if !regex.is_match(&file_name) || !file.file_type().map(|file_type| file_type.is_file()).unwrap_or(false) {
+            return None
+        }
+        let modified = file.metadata().and_then(|metadata| metadata.modified()).ok();
+        Some(Candidate { path: file.path(), file_name, modified })
+    }).collect()
+}
+
+fn captured_timestamp(regex: &Regex, file_name: &str, format: &str) -> Option<NaiveDateTime> {
+    let captures = regex.captures(file_name)?;
+    let captured = captures.name("timestamp").or_else(|| captures.get(1))?.as_str();
+    NaiveDateTime::parse_from_str(captured, format).ok()
+        .or_else(|| NaiveDate::parse_from_str(captured, format).ok().and_then(|date| date.and_hms_opt(0, 0, 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::client::testing::temp_dir;
+
+    const REGEX: &str = r"^app-(?P<timestamp>\d{4}-\d{2}-\d{2})\.log$";
+
+    fn select(selection: FileSelection, dir: &std::path::Path) -> Option<String> {
+        let path = selection.select(&dir.to_string_lossy(), REGEX)?;
+        Some(path.file_name()?.to_string_lossy().to_string())
+    }
+
+    #[test]
+    fn picks_among_matching_files() {
+        let dir = temp_dir("selection");
+        // Written oldest name last, so the newest modified file isn't the one with the greatest name
+        for (name, age) in [("app-2024-03-01.log", 30), ("app-2024-01-31.log", 20), ("app-2023-12-31.log", 10)] {
+            let file = fs::File::create(dir.join(name)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+        }
+        fs::write(dir.join("other-2025-01-01.log"), "").unwrap();
+        fs::create_dir(dir.join("app-2025-01-01.log")).unwrap();
+
+        assert_eq!(select(FileSelection::NewestModified, &dir).as_deref(), Some("app-2023-12-31.log"));
+        assert_eq!(select(FileSelection::GreatestName, &dir).as_deref(), Some("app-2024-03-01.log"));
+        assert_eq!(select(FileSelection::CapturedTimestamp { format: "%Y-%m-%d".to_string() }, &dir).as_deref(), Some("app-2024-03-01.log"));
+        assert!(select(FileSelection::FirstMatch, &dir).is_some_and(|name| name.starts_with("app-202") && !name.starts_with("app-2025")));
+        assert_eq!(matching_files(&dir.to_string_lossy(), REGEX).len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_names_without_a_timestamp() {
+        let dir = temp_dir("selection-timestamps");
+        fs::write(dir.join("app-2024-01-31.log"), "").unwrap();
+        fs::write(dir.join("app-9999-99-99.log"), "").unwrap();
+        let selection = FileSelection::CapturedTimestamp { format: "%Y-%m-%d".to_string() };
+        assert_eq!(select(selection.clone(), &dir).as_deref(), Some("app-2024-01-31.log"));
+        assert!(selection.prefers_newer());
+        assert!(!FileSelection::FirstMatch.prefers_newer());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,95 +1,120 @@
-use std::{fs, path::{Path, PathBuf}, time::{Duration, SystemTime}};
+use std::{collections::{BTreeMap, BTreeSet}, fs, io::{ErrorKind, SeekFrom}, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
 
-use log::{error, info};
-use tokio::{fs::File, io::{AsyncBufReadExt, AsyncSeekExt, BufReader}, sync::mpsc::Sender, time::{self, sleep}};
+use log::{debug, error, info};
+use tokio::{fs::File, io::{AsyncBufReadExt, AsyncSeekExt, BufReader}, sync::{mpsc::Sender, Mutex}, task::JoinHandle};
 
-use crate::message::{Message, SystemMessage, SystemMessages};
+use crate::message::{FileIdentity, Message, SystemMessage, SystemMessages};
 
-use super::{configuration::LogConfiguration, process::{match_file_name, process_line}};
+use super::{configuration::LogConfiguration, file_selection::{matching_files, FileSelection}, file_watcher::FileWatcher, line_assembler::LineAssembler, process::process_line, spool::Spool};
+
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct FileTailer {
     reader: BufReader<File>,
     path: PathBuf,
     regex: String,
     dir: String,
-    created_date_time: SystemTime
+    selection: FileSelection,
+    identity: FileIdentity,
+    position: u64,
+    last_scan: Instant,
+    single_file: bool,
+    from_beginning: bool
+}
+
+/// Whether the open file still has a name, false once it was deleted.
+#[cfg(unix)]
+fn is_linked(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() > 0
+}
+
+#[cfg(not(unix))]
+fn is_linked(_metadata: &fs::Metadata) -> bool {
+    true
+}
+
+/// What reading from the tailed file turned up.
+#[derive(Debug, PartialEq, Eq)]
+enum ReadOutcome {
+    Read,
+    /// The file shrank below the read position, e.g. logrotate's copytruncate
+    Truncated,
+    /// The file was renamed and another one created in its place
+    Rotated,
+    /// The file was deleted and another one created in its place
+    Recreated,
+    /// The file is gone, or could no longer be read
+    Removed,
+    /// A newer file matching the pattern showed up
+    Superseded
 }
 
 impl FileTailer {
-    pub async fn new(regex: String, dir: String) -> Option<Self> {
-        let mut files = match fs::read_dir(dir.clone()) {
-            Ok(files) => files,
-            Err(e) => {
-                error!("Error reading directory: {}", e);
-                return None;
-            }
-        };
-        loop {
-            let file = files.next();
-            match file {
-                Some(file) => {
-                    let file = match file {
-                        Ok(file) => file,
-                        Err(e) => {
-                            error!("Error reading file: {}", e);
-                            return None;
-                        }
-                    };
-                    let file_name = match file.file_name().into_string() {
-                        Ok(file_name) => file_name,
-                        Err(e) => {
-                            error!("Error reading file name: {:?}", e);
-                            return None;
-                        }
-                    };
-                    if match_file_name(&file_name, &regex) {
-                        let path = file.path();
-                        info!("Found new file: {:?}", path);
-                        let file = match File::open(&path).await {
-                            Ok(file) => file,
-                            Err(e) => {
-                                error!("Error opening file: {}", e);
-                                return None;
-                            }
-                        };
-                        let created_date_time = match file.metadata().await {
-                            Ok(metadata) => match metadata.created() {
-                                Ok(created_date_time) => created_date_time,
-                                Err(e) => {
-                                    error!("Error reading file creation date: {}", e);
-                                    return None;
-                                }
-                            },
-                            Err(e) => {
-                                error!("Error reading file metadata: {}", e);
-                                return None;
-                            }
-                        };
-                        let reader = BufReader::new(file);
-                        return Some(Self { reader, path, regex, dir, created_date_time })
-                    }
-                }
-                None => {
-                    break;
-                }
-            }
-        }
-        None
+    pub async fn new(regex: String, dir: String, selection: FileSelection) -> Option<Self> {
+        let path = selection.select(&dir, &regex)?;
+        info!("Found new file: {:?}", path);
+        let (reader, identity) = open(&path).await?;
+        Some(Self { reader, path, regex, dir, selection, identity, position: 0, last_scan: Instant::now(), single_file: false, from_beginning: false })
     }
 
+    /// Tailer that follows only the given file and stops once it's rotated away, instead of moving on to its successor.
+    /// `from_beginning` is for files that showed up while tailing, so nothing written to them is missed.
+    async fn for_file(path: PathBuf, regex: String, dir: String, from_beginning: bool) -> Option<Self> {
+        let (reader, identity) = open(&path).await?;
+        Some(Self {
+            reader,
+            path,
+            regex,
+            dir,
+            selection: FileSelection::FirstMatch,
+            identity,
+            position: 0,
+            last_scan: Instant::now(),
+            single_file: true,
+            from_beginning
+        })
+    }
 
-    pub async fn tail(&mut self, tx: Sender<Message>, config: LogConfiguration) {
+    pub async fn tail(&mut self, tx: Sender<Message>, config: LogConfiguration, spool: Arc<Mutex<Spool>>) {
 
-        self.reader.seek(std::io::SeekFrom::End(0)).await.map_err(|err| error!("Error seeking to end of file: {}", err)).unwrap();
+        let length = self.reader.get_ref().metadata().await.map_err(|err| error!("Error reading file metadata: {}", err)).unwrap().len();
+        // A file that showed up while tailing is new, anything spooled for its path belonged to an older file
+        let spooled = match self.from_beginning {
+            true => None,
+            false => spool.lock().await.offset(&self.path, self.identity),
+        };
+        let start = match spooled {
+            Some(offset) => {
+                let offset = match offset <= length {
+                    true => offset,
+                    false => {
+                        info!("File {:?} is shorter than the spooled offset {}, it was truncated, reading from the start", self.path, offset);
+                        0
+                    },
+                };
+                let start = offset.max(length.saturating_sub(config.get_max_catch_up_bytes()));
+                info!("Resuming {:?} from offset {}, {} bytes behind", self.path, start, length - start);
+                start
+            },
+            None if self.from_beginning => 0,
+            None => match config.get_start_from().offset(&mut self.reader, length).await {
+                Ok(start) => start,
+                Err(e) => {
+                    error!("Error finding where to start in {:?}, starting from the end: {}", self.path, e);
+                    length
+                }
+            },
+        };
+        self.position = self.reader.seek(SeekFrom::Start(start)).await.map_err(|err| error!("Error seeking file: {}", err)).unwrap();
 
         let sys_message = Message::System(SystemMessage::new(config.get_application(), SystemMessages::TailingStarted));
         tx.send(sys_message).await.map_err(|err| error!("Error sending tailing start message: {}", err)).unwrap();
 
         info!("Tailing file: {:?}", self.path);
 
-        let mut last_line = String::new();
-        let mut end_by_new_line = true;
-
+        let mut assembler = LineAssembler::new(self.position, config.get_invalid_utf8(), config.get_max_line_length());
+        let mut watcher = FileWatcher::new(&self.dir, config.get_file_watcher());
 
         'OUTER: loop {
             loop {
@@ -97,16 +122,32 @@ impl FileTailer {
                     break 'OUTER;
                 }
 
-                if !self.read_line(&tx, &mut last_line, &mut end_by_new_line, &config).await {
-                    last_line.clear();
-                    end_by_new_line = true;
-                    let sys_message = Message::System(SystemMessage::new(config.get_application(), SystemMessages::FileRemoved));
-                    if tx.is_closed() {
-                        break 'OUTER;
-                    }   
-                    tx.send(sys_message).await.map_err(|err| error!("Error sending File Removed system message: {}", err)).unwrap();
-                    break;
+                let system_message = match self.read_chunk(&tx, &mut assembler, &mut watcher, &config).await {
+                    ReadOutcome::Read => continue,
+                    ReadOutcome::Truncated => {
+                        assembler = LineAssembler::new(0, config.get_invalid_utf8(), config.get_max_line_length());
+                        let sys_message = Message::System(SystemMessage::new(config.get_application(), SystemMessages::FileTruncated));
+                        if tx.is_closed() {
+                            break 'OUTER;
+                        }
+                        tx.send(sys_message).await.map_err(|err| error!("Error sending File Truncated system message: {}", err)).unwrap();
+                        continue;
+                    },
+                    ReadOutcome::Rotated => SystemMessages::FileRotated,
+                    ReadOutcome::Recreated => SystemMessages::FileRecreated,
+                    ReadOutcome::Removed => SystemMessages::FileRemoved,
+                    ReadOutcome::Superseded => SystemMessages::NewerFileFound,
+                };
+                self.drain(&tx, &mut assembler, &config).await;
+                let sys_message = Message::System(SystemMessage::new(config.get_application(), system_message));
+                if tx.is_closed() {
+                    break 'OUTER;
+                }   
+                tx.send(sys_message).await.map_err(|err| error!("Error sending file change system message: {}", err)).unwrap();
+                if self.single_file {
+                    break 'OUTER;
                 }
+                break;
             }
 
             loop {
@@ -115,126 +156,298 @@ impl FileTailer {
                 }
 
                 if self.find_next_file().await {
+                    assembler = LineAssembler::new(0, config.get_invalid_utf8(), config.get_max_line_length());
                     let sys_message = Message::System(SystemMessage::new(config.get_application(), SystemMessages::NewFileFound));
                     tx.send(sys_message).await.map_err(|err| error!("Error sending New File Found system message: {}", err)).unwrap();
                     break;
                 }
-                time::sleep(Duration::from_millis(100)).await;
+                watcher.wait().await;
             }
         }
 
         info!("Tailing stopped");
     }
 
-    async fn read_line(&mut self, tx: &Sender<Message>, last_line: &mut String, end_by_new_line: &mut bool, config: &LogConfiguration) -> bool {
-        let mut line = String::new();
-        let bytes_read = match self.reader.read_line(&mut line).await {
+    async fn read_chunk(&mut self, tx: &Sender<Message>, assembler: &mut LineAssembler, watcher: &mut FileWatcher, config: &LogConfiguration) -> ReadOutcome {
+        let bytes_read = match self.read_available(tx, assembler, config).await {
             Ok(bytes_read) => bytes_read,
             Err(e) => {
-                error!("Error reading line: {}", e);
-                return false
+                error!("Error reading file: {}", e);
+                return ReadOutcome::Removed
             }
         };
     
         if bytes_read == 0 {
-            sleep(Duration::from_millis(100)).await;
-            let path = Path::new(&self.path);
-            let exists = Path::exists(path);
-            if !exists {
+            if let Some(line) = assembler.partial() {
+                process_line(line, &self.path, self.identity, tx, config).await;
+            }
+            watcher.wait().await;
+            return self.check_file().await;
+        }
+        ReadOutcome::Read
+    }
+
+    /// Reads what is buffered or available in the file and sends the lines it completes.
+    async fn read_available(&mut self, tx: &Sender<Message>, assembler: &mut LineAssembler, config: &LogConfiguration) -> std::io::Result<usize> {
+        let bytes = self.reader.fill_buf().await?;
+        let bytes_read = bytes.len();
+        let lines = assembler.push(bytes);
+        self.reader.consume(bytes_read);
+        self.position += bytes_read as u64;
+
+        for line in lines {
+            process_line(line, &self.path, self.identity, tx, config).await;
+        }
+        Ok(bytes_read)
+    }
+
+    /// Reads the open file to its end, it may have been written to after the last read but before it was rotated away.
+    async fn drain(&mut self, tx: &Sender<Message>, assembler: &mut LineAssembler, config: &LogConfiguration) {
+        loop {
+            match self.read_available(tx, assembler, config).await {
+                Ok(0) => break,
+                Ok(bytes_read) => debug!("Drained {} bytes from {:?}", bytes_read, self.path),
+                Err(e) => {
+                    error!("Error draining file: {}", e);
+                    break;
+                }
+            }
+        }
+        // Nothing more will be written to it, so the last line is complete even without a newline
+        if let Some(line) = assembler.finish() {
+            process_line(line, &self.path, self.identity, tx, config).await;
+        }
+    }
+
+    /// Compares what is at the tailed path with the open file, once the open file has nothing more to read.
+    async fn check_file(&mut self) -> ReadOutcome {
+        let opened = match self.reader.get_ref().metadata().await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                error!("Error reading file metadata: {}", e);
+                return ReadOutcome::Removed
+            }
+        };
+        let current = match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) => metadata,
+            // With file events this can be seen before the replacement is created
+            Err(e) if e.kind() == ErrorKind::NotFound && is_linked(&opened) => {
+                info!("File rotated: {:?}", self.path);
+                return ReadOutcome::Rotated
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {
                 info!("File removed: {:?}", self.path);
-                return false
+                return ReadOutcome::Removed
+            },
+            Err(e) => {
+                error!("Error reading file metadata: {}", e);
+                return ReadOutcome::Removed
             }
-            let file = match File::open(&self.path).await {
-                Ok(file) => file,
+        };
+
+        if FileIdentity::of(&current) != self.identity {
+            if is_linked(&opened) {
+                info!("File rotated: {:?}", self.path);
+                return ReadOutcome::Rotated
+            }
+            info!("File deleted and recreated: {:?}", self.path);
+            return ReadOutcome::Recreated
+        }
+
+        if opened.len() < self.position {
+            info!("File truncated: {:?}, reading from the start", self.path);
+            match self.reader.seek(SeekFrom::Start(0)).await {
+                Ok(position) => self.position = position,
                 Err(e) => {
-                    error!("Error opening file: {}", e);
-                    return false
+                    error!("Error seeking file: {}", e);
+                    return ReadOutcome::Removed
                 }
-            };
-            let same_file = match file.metadata().await {
-                Ok(metadata) => match metadata.created() {
-                    Ok(created_date_time) => created_date_time == self.created_date_time,
-                    Err(e) => {
-                        error!("Error reading file metadata: {}", e);
-                        return false
-                    }
-                },
+            }
+            return ReadOutcome::Truncated
+        }
+
+        if self.selection.prefers_newer() && self.last_scan.elapsed() >= RESCAN_INTERVAL {
+            self.last_scan = Instant::now();
+            if let Some(path) = self.selection.select(&self.dir, &self.regex) {
+                if path != self.path {
+                    info!("Newer file {:?} supersedes {:?}", path, self.path);
+                    return ReadOutcome::Superseded
+                }
+            }
+        }
+        ReadOutcome::Read
+    }
+
+    async fn find_next_file(&mut self) -> bool {
+        let path = match self.selection.select(&self.dir, &self.regex) {
+            Some(path) => path,
+            None => return false,
+        };
+        info!("Found file: {:?}", path);
+        let (reader, identity) = match open(&path).await {
+            Some(opened) => opened,
+            None => return false,
+        };
+        self.identity = identity;
+        self.reader = reader;
+        self.path = path;
+        self.position = 0;
+        true
+    }
+}
+
+/// Tails every file in the directory matching the pattern, picking up new ones as they show up.
+pub async fn tail_all(tx: Sender<Message>, config: LogConfiguration, spool: Arc<Mutex<Spool>>) {
+    let dir = config.get_log_file_dir();
+    let regex = config.get_log_file_name_regex();
+    let mut tailers: BTreeMap<FileIdentity, JoinHandle<()>> = BTreeMap::new();
+    let mut watcher = FileWatcher::new(&dir, config.get_file_watcher());
+    let mut first_scan = true;
+
+    while !tx.is_closed() {
+        let mut present = BTreeSet::new();
+        for path in matching_files(&dir, &regex) {
+            let identity = match fs::metadata(&path) {
+                Ok(metadata) => FileIdentity::of(&metadata),
                 Err(e) => {
                     error!("Error reading file metadata: {}", e);
-                    return false
+                    continue;
                 }
             };
-            
-            if !same_file {
-                info!("File replaced: {:?}", self.path);
-                return false
+            present.insert(identity);
+            // A rotated file keeps its identity under its new name, it was already read to its end by its tailer
+            if tailers.contains_key(&identity) {
+                continue;
             }
-        } else {
-            process_line(line, &mut *last_line, &mut *end_by_new_line, &tx, &config).await;
-        }    
-        true
+
+            info!("Found new file: {:?}", path);
+            let mut file_tailer = match FileTailer::for_file(path, regex.clone(), dir.clone(), !first_scan).await {
+                Some(file_tailer) => file_tailer,
+                None => continue,
+            };
+            let (tx, config, spool) = (tx.clone(), config.clone(), spool.clone());
+            tailers.insert(identity, tokio::spawn(async move {
+                file_tailer.tail(tx, config, spool).await;
+            }));
+        }
+        // Forget files that are gone, so their identity can be reused by new ones
+        tailers.retain(|identity, _| present.contains(identity));
+        first_scan = false;
+        watcher.wait().await;
     }
 
-    async fn find_next_file(&mut self) -> bool {
-        let mut files = match fs::read_dir(self.dir.clone()) {
-            Ok(files) => files,
-            Err(e) => {
-                error!("Error reading directory: {}", e);
-                return false
-            }
-        };
-        loop {
-            let file = files.next();
-            match file {
-                Some(file) => {
-                    let file = match file {
-                        Ok(file) => file,
-                        Err(e) => {
-                            error!("Error reading file: {}", e);
-                            return false
-                        }
-                    };
-                    let file_name = match file.file_name().into_string() {
-                        Ok(file_name) => file_name,
-                        Err(e) => {
-                            error!("Error reading file name: {:?}", e);
-                            return false
-                        }
-                    };
-                    if match_file_name(&file_name, &self.regex) {
-                        let path = file.path();
-                        info!("Found file: {:?}", path);
-                        let file = match File::open(&path).await {
-                            Ok(file) => file,
-                            Err(e) => {
-                                error!("Error opening file: {}", e);
-                                return false
-                            }
-                        };
-                        let created_date_time = match file.metadata().await {
-                            Ok(metadata) => match metadata.created() {
-                                Ok(created_date_time) => created_date_time,
-                                Err(e) => {
-                                    error!("Error reading file creation date: {}", e);
-                                    return false
-                                }
-                            },
-                            Err(e) => {
-                                error!("Error reading file metadata: {}", e);
-                                return false
-                            }
-                        };
-                        self.created_date_time = created_date_time;
-                        self.reader = BufReader::new(file);
-                        self.path = path;
-                        return true
+    for tailer in tailers.values() {
+        tailer.abort();
+    }
+    info!("Tailing stopped");
+}
+
+async fn open(path: &Path) -> Option<(BufReader<File>, FileIdentity)> {
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Error opening file: {}", e);
+            return None
+        }
+    };
+    let identity = match file.metadata().await {
+        Ok(metadata) => FileIdentity::of(&metadata),
+        Err(e) => {
+            error!("Error reading file metadata: {}", e);
+            return None
+        }
+    };
+    Some((BufReader::new(file), identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::json;
+    use tokio::{sync::mpsc, time::timeout};
+
+    use super::*;
+    use crate::client::testing::{configuration, temp_dir};
+
+    /// What checking a file that was read to its end finds after `change`, and where reading goes on.
+    async fn check_after(name: &str, change: impl FnOnce(&Path)) -> (ReadOutcome, u64) {
+        let dir = temp_dir(name);
+        let log = dir.join("app.log");
+        fs::write(&log, "first\nsecond\n").unwrap();
+        let mut tailer = FileTailer::new("^app\\.log$".to_string(), dir.to_string_lossy().to_string(), FileSelection::FirstMatch).await.unwrap();
+        tailer.position = 13;
+        change(&log);
+        let checked = (tailer.check_file().await, tailer.position);
+        fs::remove_dir_all(&dir).unwrap();
+        checked
+    }
+
+    #[tokio::test]
+    async fn keeps_reading_a_file_written_to() {
+        assert_eq!(check_after("tailer-unchanged", |_| {}).await, (ReadOutcome::Read, 13));
+        let appended = check_after("tailer-appended", |log| {
+            fs::OpenOptions::new().append(true).open(log).unwrap().write_all(b"third\n").unwrap();
+        }).await;
+        assert_eq!(appended, (ReadOutcome::Read, 13));
+    }
+
+    #[tokio::test]
+    async fn starts_over_on_a_truncated_file() {
+        assert_eq!(check_after("tailer-truncated", |log| fs::write(log, "new\n").unwrap()).await, (ReadOutcome::Truncated, 0));
+    }
+
+    #[tokio::test]
+    async fn tells_rotated_recreated_and_removed_files_apart() {
+        let rotated = check_after("tailer-rotated", |log| {
+            fs::rename(log, log.with_extension("log.1")).unwrap();
+            fs::write(log, "new\n").unwrap();
+        }).await;
+        assert_eq!(rotated.0, ReadOutcome::Rotated);
+        // Events may come before the replacement is created
+        let renamed = check_after("tailer-renamed", |log| fs::rename(log, log.with_extension("log.1")).unwrap()).await;
+        assert_eq!(renamed.0, ReadOutcome::Rotated);
+        let recreated = check_after("tailer-recreated", |log| {
+            fs::remove_file(log).unwrap();
+            fs::write(log, "new\n").unwrap();
+        }).await;
+        assert_eq!(recreated.0, ReadOutcome::Recreated);
+        assert_eq!(check_after("tailer-removed", |log| fs::remove_file(log).unwrap()).await.0, ReadOutcome::Removed);
+    }
+
+    #[tokio::test]
+    async fn tails_every_matching_file() {
+        let dir = temp_dir("tail-all");
+        fs::write(dir.join("a.log"), "before\n").unwrap();
+        fs::write(dir.join("b.log"), "").unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        let config = configuration(&dir, json!({"log_file_name_regex": r"\.log$", "tail_mode": "AllMatching"}));
+        let (tx, mut rx) = mpsc::channel(100);
+        tokio::spawn(tail_all(tx, config, Arc::new(Mutex::new(Spool::load(None)))));
+
+        let mut started = 0;
+        let mut lines = BTreeSet::new();
+        while lines.len() < 4 {
+            match timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap() {
+                Message::Data(data) => {
+                    let file_name = serde_json::to_value(&data).unwrap()["file_name"].as_str().unwrap().to_string();
+                    lines.insert((file_name, data.row().to_string()));
+                },
+                Message::System(system) if *system.message() == SystemMessages::TailingStarted => {
+                    started += 1;
+                    // Written once both files are tailed from their ends, the new file is read from its start
+                    if started == 2 {
+                        fs::OpenOptions::new().append(true).open(dir.join("a.log")).unwrap().write_all(b"one\n").unwrap();
+                        fs::OpenOptions::new().append(true).open(dir.join("b.log")).unwrap().write_all(b"two\n").unwrap();
+                        fs::write(dir.join("c.log"), "three\nfour\n").unwrap();
                     }
-                }
-                None => {
-                    break;
-                }
+                },
+                _ => {},
             }
         }
-        false
+        let expected = [("a.log", "one"), ("b.log", "two"), ("c.log", "three"), ("c.log", "four")];
+        assert_eq!(lines, expected.into_iter().map(|(file, row)| (file.to_string(), row.to_string())).collect());
+        drop(rx);
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
\ No newline at end of file
+}
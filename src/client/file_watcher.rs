@@ -0,0 +1,180 @@
+use std::time::Duration;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Even with events the files are checked this often, in case an event was missed or dropped.
+#[cfg(target_os = "linux")]
+const EVENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How the tailer learns that the files it's watching changed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileWatcherBackend {
+    /// File system events where available (inotify on Linux), polling elsewhere
+    #[default]
+    Events,
+    /// Check the files every 100ms, for file systems without events such as NFS mounts
+    Polling
+}
+
+enum Watch {
+    Polling,
+    /// Marked as changed on events in the directory
+    #[cfg(target_os = "linux")]
+    Inotify(tokio::sync::watch::Receiver<()>)
+}
+
+/// The inotify instance shared by every watcher of the process, as instances are limited per user (`fs.inotify.max_user_instances`).
+/// Each directory has one watch, however many watchers wait on it.
+#[cfg(target_os = "linux")]
+mod shared {
+    use std::{collections::HashMap, io, sync::{Arc, Mutex, OnceLock}, thread};
+
+    use inotify::{Inotify, WatchDescriptor, WatchMask, Watches};
+    use log::error;
+    use tokio::sync::watch;
+
+    /// Senders of the watched directories by watch descriptor, none once reading events failed.
+    type Directories = Arc<Mutex<Option<HashMap<WatchDescriptor, watch::Sender<()>>>>>;
+
+    struct SharedInotify {
+        watches: Watches,
+        directories: Directories
+    }
+
+    static INOTIFY: OnceLock<io::Result<SharedInotify>> = OnceLock::new();
+
+    fn lock(directories: &Directories) -> std::sync::MutexGuard<'_, Option<HashMap<WatchDescriptor, watch::Sender<()>>>> {
+        directories.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Starts reading events on a thread of its own, it outlives whichever runtime first asked for a watch.
+    fn init() -> io::Result<SharedInotify> {
+        let mut inotify = Inotify::init()?;
+        let watches = inotify.watches();
+        let directories: Directories = Arc::new(Mutex::new(Some(HashMap::new())));
+        let notified = directories.clone();
+        thread::Builder::new().name("inotify".to_string()).spawn(move || {
+            let mut buffer = [0; 4096];
+            loop {
+                let events = match inotify.read_events_blocking(&mut buffer) {
+                    Ok(events) => events,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Error reading inotify events, falling back to polling: {}", e);
+                        break;
+                    },
+                };
+                if let Some(directories) = lock(&notified).as_ref() {
+                    events.filter_map(|event| directories.get(&event.wd)).for_each(|changed| {
+                        changed.send_replace(());
+                    });
+                }
+            }
+            // Dropping the senders tells every watcher to poll instead
+            lock(&notified).take();
+        })?;
+        Ok(SharedInotify { watches, directories })
+    }
+
+    /// Receiver marked as changed on events in `dir`.
+    pub fn watch(dir: &str) -> io::Result<watch::Receiver<()>> {
+        let inotify = INOTIFY.get_or_init(init).as_ref().map_err(|e| io::Error::new(e.kind(), e.to_string()))?;
+        let mask = WatchMask::MODIFY | WatchMask::ATTRIB | WatchMask::CREATE | WatchMask::DELETE
+            | WatchMask::MOVED_FROM | WatchMask::MOVED_TO | WatchMask::DELETE_SELF | WatchMask::MOVE_SELF;
+        // Watching a directory again gives the descriptor it already has
+        let descriptor = inotify.watches.clone().add(dir, mask)?;
+        let mut directories = lock(&inotify.directories);
+        let directories = directories.as_mut().ok_or_else(|| io::Error::other("inotify stopped"))?;
+        Ok(directories.entry(descriptor).or_insert_with(|| watch::channel(()).0).subscribe())
+    }
+}
+
+/// Waits for changes in a log directory, modifications to its files as well as files being created, renamed or deleted.
+pub struct FileWatcher {
+    watch: Watch
+}
+
+impl FileWatcher {
+    pub fn new(dir: &str, backend: FileWatcherBackend) -> Self {
+        let watch = match backend {
+            FileWatcherBackend::Polling => Watch::Polling,
+            FileWatcherBackend::Events => Self::events(dir),
+        };
+        Self { watch }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn events(dir: &str) -> Watch {
+        match shared::watch(dir) {
+            Ok(changes) => {
+                info!("Watching {} with inotify", dir);
+                Watch::Inotify(changes)
+            },
+            Err(e) => {
+                error!("Error watching {} with inotify, falling back to polling: {}", dir, e);
+                Watch::Polling
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn events(_dir: &str) -> Watch {
+        Watch::Polling
+    }
+
+    /// Returns once something in the directory may have changed.
+    pub async fn wait(&mut self) {
+        match &mut self.watch {
+            Watch::Polling => sleep(POLL_INTERVAL).await,
+            #[cfg(target_os = "linux")]
+            Watch::Inotify(changes) => {
+                // Whatever happened since the last wait counts, a burst of events is a single change
+                if let Ok(Err(_)) = tokio::time::timeout(EVENT_TIMEOUT, changes.changed()).await {
+                    error!("Inotify stopped, falling back to polling");
+                    self.watch = Watch::Polling;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::client::testing::temp_dir;
+
+    #[test]
+    fn watchers_get_events_after_the_first_runtime_is_gone() {
+        let dir = temp_dir("runtimes");
+        for file in ["first.log", "second.log"] {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                let mut watcher = FileWatcher::new(&dir.to_string_lossy(), FileWatcherBackend::Events);
+                fs::write(dir.join(file), "line\n").unwrap();
+                tokio::time::timeout(EVENT_TIMEOUT / 2, watcher.wait()).await.unwrap();
+                assert!(matches!(watcher.watch, Watch::Inotify(_)));
+            });
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn watchers_share_one_inotify_instance() {
+        let dir = temp_dir("watcher");
+        // More watchers than a user gets inotify instances by default
+        let mut watchers: Vec<FileWatcher> = (0..200).map(|_| FileWatcher::new(&dir.to_string_lossy(), FileWatcherBackend::Events)).collect();
+        assert!(watchers.iter().all(|watcher| matches!(watcher.watch, Watch::Inotify(_))));
+
+        fs::write(dir.join("app.log"), "line\n").unwrap();
+        for watcher in &mut watchers {
+            // Without the event each wait would run into the timeout
+            tokio::time::timeout(EVENT_TIMEOUT / 2, watcher.wait()).await.unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use std::{fs::File, io::{BufRead, BufReader, Read}, path::{Path, PathBuf}};
+
+use chrono::{DateTime, NaiveDateTime};
+use flate2::read::MultiGzDecoder;
+use log::{error, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+
+use crate::message::{DataMessage, Message, SystemMessage, SystemMessages};
+
+use super::{configuration::LogConfiguration, line_assembler::LineAssembler};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// How to find the timestamp of a line, needed to send only part of a file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LineTimestamp {
+    /// Captures the timestamp with its `timestamp` group, or its first group when there's no such group
+    regex: String,
+    /// chrono format of the captured timestamp, taken as UTC
+    format: String
+}
+
+impl LineTimestamp {
+    fn parse(&self, regex: &Regex, line: &str) -> Option<NaiveDateTime> {
+        let captures = regex.captures(line)?;
+        let captured = captures.name("timestamp").or_else(|| captures.get(1))?.as_str();
+        NaiveDateTime::parse_from_str(captured, &self.format).ok()
+    }
+}
+
+/// Sends a file from the log directory, typically a rotated and possibly gzip compressed one, as historical data.
+/// `from` and `to` are unix nanos, lines outside of them are left out when the config knows how to timestamp lines.
+pub async fn send_file(file: String, from: Option<i64>, to: Option<i64>, tx: Sender<Message>, config: LogConfiguration) {
+    info!("Sending historical file: {}", file);
+    let application = config.get_application();
+    let finished = SystemMessages::HistoryFinished { file: file.clone() };
+
+    let blocking_tx = tx.clone();
+    let sent = tokio::task::spawn_blocking(move || read_file(&file, from, to, &blocking_tx, &config)).await;
+    match sent {
+        Ok(Ok(sent)) => info!("Sent {} historical lines", sent),
+        Ok(Err(e)) => error!("Error reading historical file: {}", e),
+        Err(e) => error!("Error joining historical file reader: {}", e),
+    }
+
+    let sys_message = Message::System(SystemMessage::new(application, finished));
+    if let Err(e) = tx.send(sys_message).await {
+        error!("Error sending History Finished system message: {}", e);
+    }
+}
+
+fn read_file(file: &str, from: Option<i64>, to: Option<i64>, tx: &Sender<Message>, config: &LogConfiguration) -> std::io::Result<usize> {
+    let path = resolve(file, &config.get_log_file_dir())?;
+    let mut reader = open(&path)?;
+
+    let from = from.map(|from| DateTime::from_timestamp_nanos(from).naive_utc());
+    let to = to.map(|to| DateTime::from_timestamp_nanos(to).naive_utc());
+    let line_timestamp = match config.get_line_timestamp() {
+        Some(line_timestamp) => match Regex::new(&line_timestamp.regex) {
+            Ok(regex) => Some((line_timestamp, regex)),
+            Err(e) => {
+                error!("Error creating line timestamp regex: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    if line_timestamp.is_none() && (from.is_some() || to.is_some()) {
+        warn!("No line_timestamp configured, sending all of {} instead of a time range", file);
+    }
+
+    let mut assembler = LineAssembler::new(0, config.get_invalid_utf8(), config.get_max_line_length());
+    let mut buffer = vec![0; 2_usize.pow(16)];
+    // Lines without a timestamp of their own, like stack traces, belong with the line before them
+    let mut timestamp = None;
+    let mut sent = 0;
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        let lines = match bytes_read {
+            0 => assembler.finish().into_iter().collect(),
+            _ => assembler.push(&buffer[..bytes_read]),
+        };
+
+        for line in lines {
+            if let Some((line_timestamp, regex)) = &line_timestamp {
+                timestamp = line_timestamp.parse(regex, &line.text).or(timestamp);
+                let after_from = from.map(|from| timestamp.is_some_and(|timestamp| timestamp >= from)).unwrap_or(true);
+                let before_to = to.map(|to| timestamp.is_some_and(|timestamp| timestamp <= to)).unwrap_or(true);
+                if !after_from || !before_to {
+                    continue;
+                }
+            }
+
+            // Empty rows are sent as a new line so the UI still shows them
+            let row = if line.text.is_empty() { "\n".to_string() } else { line.text };
+            let message = DataMessage::new(row, config.get_application(), false)
+                .with_file_name(file.to_string())
+                .historical(timestamp);
+            if tx.blocking_send(Message::Data(message)).is_err() {
+                return Ok(sent);
+            }
+            sent += 1;
+        }
+
+        if bytes_read == 0 {
+            return Ok(sent);
+        }
+    }
+}
+
+/// Only plain file names inside the log directory can be asked for.
+fn resolve(file: &str, dir: &str) -> std::io::Result<PathBuf> {
+    if Path::new(file).file_name().and_then(|name| name.to_str()) != Some(file) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("not a file name: {}", file)));
+    }
+    Ok(Path::new(dir).join(file))
+}
+
+fn open(path: &Path) -> std::io::Result<Box<dyn Read + Send>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        return Ok(Box::new(MultiGzDecoder::new(reader)));
+    }
+    Ok(Box::new(reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::client::testing::{configuration, temp_dir};
+
+    const LOG: &str = "2024-01-31 10:00:00 started\n2024-01-31 10:30:00 failed\n    at somewhere\n2024-01-31 11:30:00 stopped\n";
+
+    /// Rows read from `file`, everything sent being historical.
+    fn rows(file: &str, from: Option<&str>, to: Option<&str>, config: &LogConfiguration) -> std::io::Result<Vec<String>> {
+        let nanos = |timestamp: &str| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S").unwrap().and_utc().timestamp_nanos_opt().unwrap();
+        let (tx, mut rx) = mpsc::channel(100);
+        read_file(file, from.map(nanos), to.map(nanos), &tx, config)?;
+        drop(tx);
+        let mut rows = vec![];
+        while let Ok(Message::Data(data)) = rx.try_recv() {
+            assert!(data.is_historical());
+            rows.push(data.row().to_string());
+        }
+        Ok(rows)
+    }
+
+    #[test]
+    fn reads_plain_and_gzip_compressed_files() {
+        let dir = temp_dir("history");
+        fs::write(dir.join("app.log.1"), LOG).unwrap();
+        let mut gzip = GzEncoder::new(fs::File::create(dir.join("app.log.2.gz")).unwrap(), Compression::default());
+        gzip.write_all(LOG.as_bytes()).unwrap();
+        gzip.finish().unwrap();
+        let config = configuration(&dir, json!({}));
+
+        assert_eq!(rows("app.log.1", None, None, &config).unwrap().len(), 4);
+        assert_eq!(rows("app.log.2.gz", None, None, &config).unwrap(), rows("app.log.1", None, None, &config).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sends_lines_within_the_time_range_with_their_continuations() {
+        let dir = temp_dir("history-range");
+        fs::write(dir.join("app.log.1"), LOG).unwrap();
+        let config = configuration(&dir, json!({"line_timestamp": {"regex": r"^(\S+ \S+)", "format": "%Y-%m-%d %H:%M:%S"}}));
+
+        let within = rows("app.log.1", Some("2024-01-31 10:15:00"), Some("2024-01-31 11:00:00"), &config).unwrap();
+        assert_eq!(within, ["2024-01-31 10:30:00 failed", "    at somewhere"]);
+        // Without a way to timestamp lines the whole file is sent
+        let all = rows("app.log.1", Some("2024-01-31 10:15:00"), None, &configuration(&dir, json!({}))).unwrap();
+        assert_eq!(all.len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_reads_files_in_the_log_directory() {
+        let dir = temp_dir("history-resolve");
+        let config = configuration(&dir.join("logs"), json!({}));
+        fs::create_dir(dir.join("logs")).unwrap();
+        fs::write(dir.join("secret"), "password\n").unwrap();
+        for file in ["../secret", "/etc/passwd", ""] {
+            assert_eq!(rows(file, None, None, &config).unwrap_err().kind(), std::io::ErrorKind::InvalidInput, "{}", file);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
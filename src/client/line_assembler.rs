@@ -0,0 +1,291 @@
+use serde::{Deserialize, Serialize};
+
+const TRUNCATION_MARKER: &str = " [truncated]";
+
+/// How bytes that are not valid UTF-8 are shown.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Replaced with U+FFFD
+    #[default]
+    Lossy,
+    /// Written as `\xNN`
+    HexEscape
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledLine {
+    pub text: String,
+    /// The line has no newline yet, it will be sent again once more of it is written
+    pub partial: bool,
+    /// The line replaces the partial one sent before it
+    pub replaces_partial: bool,
+    /// The line was longer than the maximum line length and was cut
+    pub truncated: bool,
+    /// Offset in the file right after this line, including its newline
+    pub end_offset: u64
+}
+
+/// Assembles lines out of the raw bytes read from a file, which may end anywhere in a line.
+pub struct LineAssembler {
+    buffer: Vec<u8>,
+    offset: u64,
+    truncated: bool,
+    partial_sent: Option<usize>,
+    invalid_utf8: InvalidUtf8,
+    max_line_length: usize
+}
+
+impl LineAssembler {
+    /// `offset` is the position in the file of the first byte that will be pushed.
+    pub fn new(offset: u64, invalid_utf8: InvalidUtf8, max_line_length: usize) -> Self {
+        Self { buffer: Vec::new(), offset, truncated: false, partial_sent: None, invalid_utf8, max_line_length: max_line_length.max(1) }
+    }
+
+    /// Consumes the bytes and returns the lines they complete.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<AssembledLine> {
+        let mut lines = vec![];
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let (chunk, complete) = match rest.iter().position(|byte| *byte == b'\n') {
+                Some(newline) => (&rest[..newline], true),
+                None => (rest, false),
+            };
+            self.offset += chunk.len() as u64;
+            self.append(chunk);
+
+            if complete {
+                self.offset += 1;
+                if self.buffer.last() == Some(&b'\r') {
+                    self.buffer.pop();
+                }
+                lines.push(self.line(false));
+                self.buffer.clear();
+                self.truncated = false;
+                self.partial_sent = None;
+                rest = &rest[chunk.len() + 1..];
+            } else {
+                rest = &[];
+            }
+        }
+        lines
+    }
+
+    /// Returns the line still waiting for its newline, if anything was added to it since it was last returned.
+    pub fn partial(&mut self) -> Option<AssembledLine> {
+        if self.buffer.is_empty() || self.partial_sent == Some(self.buffer.len()) {
+            return None;
+        }
+        let mut line = self.line(true);
+        // A carriage return may be the first half of a CRLF still being written
+        if !self.truncated && line.text.ends_with('\r') {
+            line.text.pop();
+        }
+        self.partial_sent = Some(self.buffer.len());
+        Some(line)
+    }
+
+    /// Returns the line still waiting for its newline as a complete line, for when nothing more will be written.
+    pub fn finish(&mut self) -> Option<AssembledLine> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let line = self.line(false);
+        self.buffer.clear();
+        self.truncated = false;
+        self.partial_sent = None;
+        Some(line)
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        if self.truncated {
+            return;
+        }
+        let room = self.max_line_length - self.buffer.len().min(self.max_line_length);
+        if bytes.len() > room {
+            self.buffer.extend_from_slice(&bytes[..room]);
+            self.truncated = true;
+        } else {
+            self.buffer.extend_from_slice(bytes);
+        }
+    }
+
+    fn line(&self, partial: bool) -> AssembledLine {
+        let mut text = decode(&self.buffer, self.invalid_utf8);
+        if self.truncated {
+            text.push_str(TRUNCATION_MARKER);
+        }
+        AssembledLine {
+            text,
+            partial,
+            replaces_partial: self.partial_sent.is_some(),
+            truncated: self.truncated,
+            end_offset: self.offset
+        }
+    }
+}
+
+fn decode(bytes: &[u8], invalid_utf8: InvalidUtf8) -> String {
+    if invalid_utf8 == InvalidUtf8::Lossy {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+
+    let mut text = String::with_capacity(bytes.len());
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return text;
+            },
+            Err(err) => {
+                let (valid, invalid) = rest.split_at(err.valid_up_to());
+                // Checked by from_utf8 above
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                let invalid_length = err.error_len().unwrap_or(invalid.len());
+                for byte in &invalid[..invalid_length] {
+                    text.push_str(&format!("\\x{:02X}", byte));
+                }
+                rest = &invalid[invalid_length..];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[AssembledLine]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    #[test]
+    fn splits_complete_lines() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 1024);
+        let lines = assembler.push(b"first\nsecond\n\nthird\n");
+        assert_eq!(texts(&lines), vec!["first", "second", "", "third"]);
+        assert!(lines.iter().all(|line| !line.partial && !line.replaces_partial && !line.truncated));
+        assert_eq!(lines.iter().map(|line| line.end_offset).collect::<Vec<_>>(), vec![6, 13, 14, 20]);
+        assert_eq!(assembler.partial(), None);
+    }
+
+    #[test]
+    fn strips_crlf() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 1024);
+        let lines = assembler.push(b"first\r\nsecond\r\n");
+        assert_eq!(texts(&lines), vec!["first", "second"]);
+        assert_eq!(lines[1].end_offset, 15);
+    }
+
+    #[test]
+    fn strips_crlf_split_across_reads() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 1024);
+        assert!(assembler.push(b"first\r").is_empty());
+        assert_eq!(assembler.partial().map(|line| line.text), Some("first".to_string()));
+        let lines = assembler.push(b"\n");
+        assert_eq!(texts(&lines), vec!["first"]);
+        assert!(lines[0].replaces_partial);
+    }
+
+    #[test]
+    fn keeps_lone_carriage_returns() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 1024);
+        assert_eq!(texts(&assembler.push(b"a\rb\n")), vec!["a\rb"]);
+    }
+
+    #[test]
+    fn keeps_sentinel_characters() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 1024);
+        let line = "👻🛸👻 ghost 👻\n";
+        assert_eq!(texts(&assembler.push(line.as_bytes())), vec!["👻🛸👻 ghost 👻"]);
+    }
+
+    #[test]
+    fn replaces_partial_lines_until_complete() {
+        let mut assembler = LineAssembler::new(10, InvalidUtf8::Lossy, 1024);
+        assert!(assembler.push(b"hello").is_empty());
+
+        let partial = assembler.partial().unwrap();
+        assert_eq!(partial.text, "hello");
+        assert!(partial.partial);
+        assert!(!partial.replaces_partial);
+        assert_eq!(assembler.partial(), None);
+
+        assert!(assembler.push(b" wor").is_empty());
+        let partial = assembler.partial().unwrap();
+        assert_eq!(partial.text, "hello wor");
+        assert!(partial.replaces_partial);
+
+        let lines = assembler.push(b"ld\nnext");
+        assert_eq!(texts(&lines), vec!["hello world"]);
+        assert!(!lines[0].partial);
+        assert!(lines[0].replaces_partial);
+        assert_eq!(lines[0].end_offset, 22);
+
+        let partial = assembler.partial().unwrap();
+        assert_eq!(partial.text, "next");
+        assert!(!partial.replaces_partial);
+    }
+
+    #[test]
+    fn finishes_lines_without_newline() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 1024);
+        assert_eq!(texts(&assembler.push(b"done\nlast")), vec!["done"]);
+        assert!(assembler.partial().is_some());
+
+        let line = assembler.finish().unwrap();
+        assert_eq!(line.text, "last");
+        assert!(!line.partial);
+        assert!(line.replaces_partial);
+        assert_eq!(line.end_offset, 9);
+        assert_eq!(assembler.finish(), None);
+        assert_eq!(assembler.partial(), None);
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_lossy() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 1024);
+        assert_eq!(texts(&assembler.push(b"bad \xff\xfe byte\n")), vec!["bad \u{FFFD}\u{FFFD} byte"]);
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_hex_escaped() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::HexEscape, 1024);
+        assert_eq!(texts(&assembler.push(b"bad \xff\xfe byte \xc3\xa9\n")), vec!["bad \\xFF\\xFE byte é"]);
+    }
+
+    #[test]
+    fn decodes_multi_byte_characters_split_across_reads() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::HexEscape, 1024);
+        assert!(assembler.push(b"caf\xc3").is_empty());
+        assert_eq!(texts(&assembler.push(b"\xa9\n")), vec!["café"]);
+    }
+
+    #[test]
+    fn truncates_long_lines() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 4);
+        let lines = assembler.push(b"abcdefgh\nijkl\n");
+        assert_eq!(texts(&lines), vec!["abcd [truncated]", "ijkl"]);
+        assert!(lines[0].truncated);
+        assert!(!lines[1].truncated);
+        assert_eq!(lines[0].end_offset, 9);
+        assert_eq!(lines[1].end_offset, 14);
+    }
+
+    #[test]
+    fn truncates_long_lines_across_reads() {
+        let mut assembler = LineAssembler::new(0, InvalidUtf8::Lossy, 4);
+        assert!(assembler.push(b"abc").is_empty());
+        assert!(assembler.push(b"defg").is_empty());
+        let partial = assembler.partial().unwrap();
+        assert_eq!(partial.text, "abcd [truncated]");
+        assert!(partial.truncated);
+        assert!(assembler.push(b"hij").is_empty());
+        assert_eq!(assembler.partial(), None);
+
+        let lines = assembler.push(b"\n");
+        assert_eq!(texts(&lines), vec!["abcd [truncated]"]);
+        assert!(lines[0].replaces_partial);
+        assert_eq!(lines[0].end_offset, 11);
+    }
+}
@@ -1,3 +1,16 @@
 pub mod process;
+pub mod acknowledgements;
+pub mod backoff;
+pub mod batch;
+pub mod compression;
 pub mod configuration;
-pub mod file_tailer;
\ No newline at end of file
+pub mod file_tailer;
+pub mod file_selection;
+pub mod file_watcher;
+pub mod history;
+pub mod line_assembler;
+pub mod spool;
+pub mod start_position;
+#[cfg(test)]
+mod testing;
+pub mod tls;
\ No newline at end of file
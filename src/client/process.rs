@@ -1,28 +1,50 @@
+use std::{collections::VecDeque, path::Path, sync::Arc, time::Duration};
+
 use log::{debug, error, info, warn};
-use tokio::{sync::mpsc::Sender, time};
-use tokio_tungstenite::connect_async;
-use futures_util::{SinkExt, StreamExt};
-use tungstenite::{handshake::client::generate_key, http::Request, Message, Error};
+use tokio::{sync::{mpsc::Sender, Mutex}, time::{self, Instant}};
+use tokio_tungstenite::connect_async_tls_with_config;
+use futures_util::{Sink, SinkExt, StreamExt};
+use tungstenite::{handshake::client::generate_key, http::Request, protocol::frame::coding::CloseCode, Message, Error};
 
-use crate::{client::file_tailer::FileTailer, message::{self, BinaryMessage, DataMessage}};
+use crate::{client::{acknowledgements::Unacknowledged, backoff::Backoff, batch::Batch, compression::Compressor, configuration::TailMode, file_tailer::{self, FileTailer}, history, line_assembler::AssembledLine, spool::Spool}, message::{self, handshake::{self, Feature, Handshake, PausePolicy}, BinaryMessage, DataMessage, FileIdentity, FileOffset}};
 
 use super::configuration::LogConfiguration;
 
 pub async fn file(config: LogConfiguration) {
+    let spool = Arc::new(Mutex::new(Spool::load(config.get_spool_file())));
+    let unacknowledged = Arc::new(Mutex::new(Unacknowledged::default()));
+    let reconnect = config.get_reconnect();
+    let mut backoff = Backoff::new(reconnect);
     loop {
-        process_until_error(config.clone()).await;
-        time::sleep(time::Duration::from_secs(20)).await;
+        let attempt = backoff.attempt() + 1;
+        info!("connection attempt {}", attempt);
+        let started = Instant::now();
+        match process_until_error(config.clone(), spool.clone(), unacknowledged.clone()).await {
+            true if started.elapsed() >= reconnect.stable_after() => {
+                info!("connection attempt {} succeeded, connection lost after {:?}", attempt, started.elapsed());
+                backoff.reset();
+            },
+            true => info!("connection attempt {} succeeded, but the connection only lasted {:?}", attempt, started.elapsed()),
+            false => warn!("connection attempt {} failed", attempt),
+        }
+        let delay = backoff.next_delay();
+        info!("reconnecting in {:?}", delay);
+        time::sleep(delay).await;
     }
 }
 
-async fn process_until_error(config: LogConfiguration) {
+/// Connects and ships lines until the connection is lost, returns whether it got connected at all.
+async fn process_until_error(config: LogConfiguration, spool: Arc<Mutex<Spool>>, unacknowledged: Arc<Mutex<Unacknowledged>>) -> bool {
     let host = config.get_server_host();
     let port = config.get_server_port();
     let path = config.get_server_path();
     let host = if port == 0 { host } else { format!("{}:{}", host, port) };
-    let uri = format!("ws://{}/{}", host, path);
+    let tls = config.get_tls();
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let uri = format!("{}://{}/{}", scheme, host, path);
 
     info!("connecting to {}", uri);
+    let client_id = unacknowledged.lock().await.client_id().to_string();
     // Connect to WebSocket server
     let request = Request::builder()
         .uri(uri)
@@ -32,28 +54,60 @@ async fn process_until_error(config: LogConfiguration) {
         .header("Upgrade", "websocket")
         .header("Connection", "Upgrade")
         .header("Application", config.get_application().to_string())
+        .header(handshake::PROTOCOL_VERSION_HEADER, Handshake::default().version_header())
+        .header(handshake::FEATURES_HEADER, Handshake::default().features_header())
+        .header(handshake::CLIENT_ID_HEADER, client_id)
+        .header(handshake::PAUSE_POLICY_HEADER, config.get_pause_policy().header());
+    let request = match config.get_token() {
+        Some(token) => request.header("Authorization", format!("Bearer {}", token)),
+        None => request,
+    };
+    let request = request
         .body(())
         .map_err(|err| error!("Error creating request: {}", err))
         .unwrap();
     
-    let (ws_stream, _) = match connect_async(request).await {
+    let connector = match tls.map(|tls| tls.connector()).transpose() {
+        Ok(connector) => connector,
+        Err(err) => {
+            error!("Error setting up TLS: {}", err);
+            return false;
+        },
+    };
+
+    let (ws_stream, response) = match connect_async_tls_with_config(request, None, false, connector).await {
         Ok(data) => data,
         Err(err) => {
             error!("Error connecting to WebSocket server: {}", err);
-            return;
+            return false;
         },
     };
 
     info!("webSocket connected");
+
+    // Servers from before the handshake don't answer it, they speak the legacy protocol
+    let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+    let handshake = Handshake::from_headers(header(handshake::PROTOCOL_VERSION_HEADER), header(handshake::FEATURES_HEADER))
+        .and_then(|server| Handshake::default().negotiate(&server));
+    let handshake = match handshake {
+        Ok(handshake) => handshake,
+        Err(reason) => {
+            error!("Incompatible server: {}", reason);
+            return false;
+        }
+    };
+    info!("Agreed on {}", handshake);
     
     // Split the WebSocket stream
-    let (mut write, mut read) = ws_stream.split();
+    let (write, mut read) = ws_stream.split();
     let (tx, mut rx) = tokio::sync::mpsc::channel(config.get_channel_buffer());
     let (tx_client_abort, mut rx_client_abort) = tokio::sync::mpsc::channel::<()>(1);
     let (tx_server_abort, mut rx_server_abort) = tokio::sync::mpsc::channel::<()>(1);
     
     // Spawn a task to handle incoming messages
     let tx_clone = tx.clone();
+    let receive_config = config.clone();
+    let (receive_unacknowledged, receive_spool) = (unacknowledged.clone(), spool.clone());
     let receive_task = tokio::spawn(async move {
         let mut abort_send_task = true;
         loop {
@@ -64,7 +118,7 @@ async fn process_until_error(config: LogConfiguration) {
                     break;
                 },
                 message = read.next() => {
-                    if message.is_some() && !process_message(message.unwrap(), &tx_clone).await {
+                    if message.is_some() && !process_message(message.unwrap(), &tx_clone, &receive_config, &receive_unacknowledged, &receive_spool).await {
                         break;
                     }
                 }
@@ -82,30 +136,42 @@ async fn process_until_error(config: LogConfiguration) {
         info!("client receive task stopped");
     });
 
-    let file_tailer = FileTailer::new(config.get_log_file_name_regex(), config.get_log_file_dir()).await;
+    let pause_policy = config.get_pause_policy();
+    let batch = Batch::new(config.get_batch_max_bytes(), Duration::from_millis(config.get_batch_linger_ms()));
+    let tailer_spool = spool.clone();
+    match config.get_tail_mode() {
+        TailMode::Single => {
+            let file_tailer = FileTailer::new(config.get_log_file_name_regex(), config.get_log_file_dir(), config.get_file_selection()).await;
 
-    match file_tailer {
-        Some(mut file_tailer) => {
-            tokio::spawn(async move {
-                file_tailer.tail(tx, config).await;
-            });
-        }
-        None => {
-            error!("No file found. Waiting for a file");
-            tokio::spawn(async move {
-                let mut file_tailer = loop {
-                    let file_tailer = FileTailer::new(config.get_log_file_name_regex(), config.get_log_file_dir()).await;
-                    match file_tailer {
-                        Some(file_tailer) => {
-                            break file_tailer
-                        }
-                        None => {
-                            time::sleep(time::Duration::from_secs(2)).await;
-                        }
-                    }
-                };
+            match file_tailer {
+                Some(mut file_tailer) => {
+                    tokio::spawn(async move {
+                        file_tailer.tail(tx, config, tailer_spool).await;
+                    });
+                }
+                None => {
+                    error!("No file found. Waiting for a file");
+                    tokio::spawn(async move {
+                        let mut file_tailer = loop {
+                            let file_tailer = FileTailer::new(config.get_log_file_name_regex(), config.get_log_file_dir(), config.get_file_selection()).await;
+                            match file_tailer {
+                                Some(file_tailer) => {
+                                    break file_tailer
+                                }
+                                None => {
+                                    time::sleep(time::Duration::from_secs(2)).await;
+                                }
+                            }
+                        };
 
-                file_tailer.tail(tx, config).await;
+                        file_tailer.tail(tx, config, tailer_spool).await;
+                    });
+                }
+            }
+        },
+        TailMode::AllMatching => {
+            tokio::spawn(async move {
+                file_tailer::tail_all(tx, config, tailer_spool).await;
             });
         }
     }
@@ -114,14 +180,34 @@ async fn process_until_error(config: LogConfiguration) {
     let send_task = tokio::spawn(async move {
         // Keep the connection alive
         let mut send = false;
+        let mut started = false;
+        let mut pending = Vec::new();
         let mut abort_receive_task= true;
-        loop {
+        let batching = handshake.supports(Feature::Batching);
+        let file_data = handshake.version >= handshake::FILE_EVENTS_VERSION;
+        let mut paused = VecDeque::new();
+        let mut batch = batch;
+        let mut outbound = Outbound {
+            write,
+            compressor: Compressor::new(handshake.supports(Feature::Compression)),
+            acknowledged: handshake.supports(Feature::Ack),
+            unacknowledged,
+            spool
+        };
+        'SEND: loop {
             let msg = tokio::select! {
                 _ = rx_server_abort.recv() => {
                     info!("client send task aborted");
                     abort_receive_task = false;
                     break;
                 },
+                _ = time::sleep_until(batch.deadline().unwrap_or_else(Instant::now)), if batch.deadline().is_some() => {
+                    let (binary_message, file_offsets) = batch.take();
+                    if !outbound.write(binary_message, file_offsets, send).await {
+                        break;
+                    }
+                    continue;
+                },
                 msg = rx.recv() => {
                     match msg {
                         Some(msg) => msg,
@@ -130,45 +216,111 @@ async fn process_until_error(config: LogConfiguration) {
                 }
             };
 
-            if msg.system().is_some() {
-                match msg.system().unwrap().message() {
-                    message::SystemMessages::Stop => {
-                        info!("stopped sending messages");
-                        break;
-                    }, 
-                    message::SystemMessages::Start => {
-                        info!("starting to send messages");
-                        send = true;
-                    },
-                    message::SystemMessages::Pause => {
-                        info!("paused sending messages");
-                        send = false;
-                    },
-                    message::SystemMessages::Resume => {
-                        info!("resumed sending messages");
-                        send = true;
+            // Hold back what the tailer reads before the server starts us, it may be catching up from the spool
+            let mut messages = if started {
+                VecDeque::from([msg])
+            } else {
+                match msg.system().map(|sys| sys.message()) {
+                    Some(message::SystemMessages::Start) => {
+                        started = true;
+                        let mut messages = VecDeque::from([msg]);
+                        messages.extend(pending.drain(..));
+                        messages
                     },
-                    _ => {}
+                    _ => {
+                        pending.push(msg);
+                        continue;
+                    }
                 }
-            }
-            let binary_message = BinaryMessage::from(msg);
-            let binary_msg = match borsh::to_vec(&binary_message) {
-                Ok(msg) => msg,
-                Err(err) => {
-                    error!("Failed to serialize message: {}", err);
-                    continue;
-                },
             };
 
+            while let Some(msg) = messages.pop_front() {
+                // Lines read while paused are dropped, unless the config keeps the last of them for the resume
+                if let (false, Some(_), PausePolicy::Buffer(lines)) = (send, msg.data(), pause_policy) {
+                    paused.push_back(msg);
+                    if paused.len() > lines {
+                        if let Some(dropped) = paused.pop_front() {
+                            let file_offsets = dropped.data().and_then(|data| data.file_offset()).cloned().into_iter().collect();
+                            outbound.commit(file_offsets).await;
+                        }
+                    }
+                    continue;
+                }
 
-            if send {
-                if let Err(e) = write.send(Message::Binary(binary_msg)).await {
-                    error!("Error sending message: {}", e);
-                    break;
+                let msg = match msg {
+                    crate::message::Message::Data(data) if batching => {
+                        if batch.overflows(&data) {
+                            let (binary_message, file_offsets) = batch.take();
+                            if !outbound.write(binary_message, file_offsets, send).await {
+                                break 'SEND;
+                            }
+                        }
+                        batch.push(data);
+                        if batch.is_full() {
+                            let (binary_message, file_offsets) = batch.take();
+                            if !outbound.write(binary_message, file_offsets, send).await {
+                                break 'SEND;
+                            }
+                        }
+                        continue;
+                    },
+                    // Older servers can't read the file name of live lines, those asking for history know about it
+                    crate::message::Message::Data(data) if !file_data && !data.is_historical() => crate::message::Message::Data(data.without_file_name()),
+                    // Nor the system messages added since, there's nothing to tell them instead
+                    crate::message::Message::System(sys) if sys.message().protocol_version() > handshake.version => {
+                        debug!("Not sending {:?} to a server speaking protocol version {}", sys.message(), handshake.version);
+                        continue;
+                    },
+                    msg => msg,
+                };
+
+                // Everything else goes out after the lines batched before it
+                if !batch.is_empty() {
+                    let (binary_message, file_offsets) = batch.take();
+                    if !outbound.write(binary_message, file_offsets, send).await {
+                        break 'SEND;
+                    }
+                }
+
+                if msg.system().is_some() {
+                    match msg.system().unwrap().message() {
+                        message::SystemMessages::Stop => {
+                            info!("stopped sending messages");
+                            break 'SEND;
+                        }, 
+                        message::SystemMessages::Start => {
+                            info!("starting to send messages");
+                            send = true;
+                            if !outbound.resend().await {
+                                break 'SEND;
+                            }
+                        },
+                        message::SystemMessages::Pause => {
+                            info!("paused sending messages");
+                            send = false;
+                        },
+                        message::SystemMessages::Resume => {
+                            info!("resumed sending messages, {} lines kept while paused", paused.len());
+                            send = true;
+                            // The kept lines go out right after the resume, ahead of anything read since
+                            while let Some(kept) = paused.pop_back() {
+                                messages.push_front(kept);
+                            }
+                        },
+                        _ => {}
+                    }
+                }
+                let file_offsets = msg.data().and_then(|data| data.file_offset()).cloned().into_iter().collect();
+                if !outbound.write(BinaryMessage::from(msg), file_offsets, send).await {
+                    break 'SEND;
                 }
             }
         }
 
+        outbound.spool.lock().await.persist();
+        let compressor = &outbound.compressor;
+        info!("sent {} bytes for {} bytes of messages, compression ratio {:.2}", compressor.sent_bytes(), compressor.uncompressed_bytes(), compressor.ratio());
+
         if abort_receive_task {
             match tx_client_abort.send(()).await {
                 Ok(_) => {},
@@ -182,14 +334,110 @@ async fn process_until_error(config: LogConfiguration) {
 
     let _ = tokio::join!(receive_task, send_task);
     info!("client stopped");
+    true
+}
+
+/// Sending side of a connection, along with what was agreed on for it.
+struct Outbound<W> {
+    write: W,
+    compressor: Compressor,
+    /// Whether the server acknowledges frames, otherwise they are committed once written
+    acknowledged: bool,
+    unacknowledged: Arc<Mutex<Unacknowledged>>,
+    spool: Arc<Mutex<Spool>>
 }
 
-async fn process_message(message: Result<Message, Error>, tx_clone: &Sender<crate::message::Message>) -> bool {
+impl<W: Sink<Message, Error = Error> + Unpin> Outbound<W> {
+    /// Writes a message unless paused and commits the offsets of the lines in it, or keeps them until the server acknowledges it.
+    /// Returns false if the connection is gone.
+    async fn write(&mut self, binary_message: BinaryMessage, file_offsets: Vec<FileOffset>, send: bool) -> bool {
+        // Lines discarded while paused count as handled too, they are not meant to be sent later
+        if !send {
+            self.commit(file_offsets).await;
+            return true;
+        }
+
+        let binary_msg = match borsh::to_vec(&binary_message) {
+            Ok(msg) => msg,
+            Err(err) => {
+                error!("Failed to serialize message: {}", err);
+                return true;
+            },
+        };
+        if !self.acknowledged {
+            let written = self.write_bytes(binary_msg).await;
+            if written {
+                self.commit(file_offsets).await;
+            }
+            return written;
+        }
+
+        let sequence = self.unacknowledged.lock().await.push(binary_msg.clone(), file_offsets.clone());
+        let mut spool = self.spool.lock().await;
+        for file_offset in file_offsets {
+            spool.sent(&file_offset);
+        }
+        drop(spool);
+        self.write_sequenced(sequence, binary_msg).await
+    }
+
+    /// Sends again what the server didn't acknowledge before the connection was lost.
+    /// A server that no longer acknowledges gets it as plain messages.
+    async fn resend(&mut self) -> bool {
+        if self.acknowledged {
+            let frames = self.unacknowledged.lock().await.frames();
+            if !frames.is_empty() {
+                info!("resending {} unacknowledged frames", frames.len());
+            }
+            for (sequence, binary_msg) in frames {
+                if !self.write_sequenced(sequence, binary_msg).await {
+                    return false;
+                }
+            }
+            return true;
+        }
+
+        let frames = self.unacknowledged.lock().await.take();
+        for (binary_msg, file_offsets) in frames {
+            if !self.write_bytes(binary_msg).await {
+                return false;
+            }
+            self.commit(file_offsets).await;
+        }
+        true
+    }
+
+    async fn write_sequenced(&mut self, sequence: u64, binary_msg: Vec<u8>) -> bool {
+        match borsh::to_vec(&BinaryMessage::Sequenced(sequence, binary_msg)) {
+            Ok(msg) => self.write_bytes(msg).await,
+            Err(err) => {
+                error!("Failed to serialize message: {}", err);
+                true
+            },
+        }
+    }
+
+    async fn write_bytes(&mut self, binary_msg: Vec<u8>) -> bool {
+        if let Err(e) = self.write.send(Message::Binary(self.compressor.encode(binary_msg))).await {
+            error!("Error sending message: {}", e);
+            return false;
+        }
+        true
+    }
+
+    async fn commit(&self, file_offsets: Vec<FileOffset>) {
+        let mut spool = self.spool.lock().await;
+        for file_offset in file_offsets {
+            spool.commit(&file_offset);
+        }
+    }
+}
+
+async fn process_message(message: Result<Message, Error>, tx_clone: &Sender<crate::message::Message>, config: &LogConfiguration, unacknowledged: &Mutex<Unacknowledged>, spool: &Mutex<Spool>) -> bool {
     match message {
         Ok(msg) => {
             match msg {
                 Message::Text(text) => {
-                    info!("Received: {}", text);
                     let message: crate::message::Message = match serde_json::from_str(&text) {
                         Ok(message) => message,
                         Err(e) => {
@@ -197,13 +445,32 @@ async fn process_message(message: Result<Message, Error>, tx_clone: &Sender<crat
                             return true
                         },
                     };
+                    // Acks come for every frame and only concern the spool
+                    if let Some(message::SystemMessages::Ack { sequence }) = message.system().map(|sys| sys.message()) {
+                        debug!("Received ack: {}", sequence);
+                        let file_offsets = unacknowledged.lock().await.acknowledge(*sequence);
+                        let mut spool = spool.lock().await;
+                        for file_offset in file_offsets {
+                            spool.commit(&file_offset);
+                        }
+                        return true
+                    }
+                    info!("Received: {}", text);
+                    // History is read next to tailing, the request itself has nothing to tell the send task
+                    if let Some(message::SystemMessages::ReadHistory { file, from, to }) = message.system().map(|sys| sys.message()) {
+                        tokio::spawn(history::send_file(file.clone(), *from, *to, tx_clone.clone(), config.clone()));
+                        return true
+                    }
                     tx_clone.send(message).await.map_err(|err| error!("Error sending message: {}", err)).ok();
                 },
                 Message::Binary(data) => info!("Received binary data: {:?}", data),
                 Message::Ping(_) => debug!("Received ping"),
                 Message::Pong(_) => debug!("Received pong"),
-                Message::Close(_) => {
-                    info!("Server closed connection");
+                Message::Close(frame) => {
+                    match frame {
+                        Some(frame) if frame.code != CloseCode::Normal => error!("Server closed connection: {} ({})", frame.reason, frame.code),
+                        _ => info!("Server closed connection"),
+                    }
                     return false
                 },
                 Message::Frame(_) => warn!("Received raw frame"),
@@ -218,69 +485,26 @@ async fn process_message(message: Result<Message, Error>, tx_clone: &Sender<crat
     true
 }
 
-pub(crate) async fn process_line(mut line: String, last_line: &mut String, end_by_new_line: &mut bool, tx: &Sender<crate::message::Message>, config: &LogConfiguration) {
-    let replacent: &str = "👻🛸👻";
-    line = line.replace('\n', replacent);
-
-    // Check if line is a new line
-    if line.eq("👻🛸👻") {
-        *end_by_new_line = true;
-        let message = DataMessage::new("\n".to_string(), config.get_application(), false);
-        let message = crate::message::Message::Data(message);
-        if tx.is_closed() {
-            return;
-        }
-        match tx.send(message).await{
-            Ok(_) => {}
-            Err(e) => {
-                error!("Error sending message: {}", e);
-                return;
-            }
+pub(crate) async fn process_line(line: AssembledLine, path: &Path, identity: FileIdentity, tx: &Sender<crate::message::Message>, config: &LogConfiguration) {
+    // Empty rows are sent as a new line so the UI still shows them
+    let row = if line.text.is_empty() { "\n".to_string() } else { line.text };
+    let mut message = DataMessage::new(row, config.get_application(), line.replaces_partial);
+    // Single file configs leave the file name out, so they keep working with servers that don't know about it
+    if config.get_tail_mode() == TailMode::AllMatching {
+        if let Some(file_name) = path.file_name() {
+            message = message.with_file_name(file_name.to_string_lossy().to_string());
         }
+    }
+    // Only complete lines are safe to resume after, a partial one would be split on reconnect
+    if !line.partial {
+        message = message.with_file_offset(FileOffset { path: path.to_path_buf(), identity, offset: line.end_offset });
+    }
+    debug!("{}", message.row());
+    let message = crate::message::Message::Data(message);
+    if tx.is_closed() {
         return;
     }
-
-    let lines = line.split('👻');
-    for mut line in lines {
-        if line.eq("") {
-            continue;
-        }
-        if line.eq("🛸") {
-            *end_by_new_line = true;
-            continue;
-        }
-        let append = if *end_by_new_line {
-            *end_by_new_line = false;
-            false
-        } else {
-            last_line.push_str(line);
-            line = &last_line;
-            true
-        };
-        let message = DataMessage::new(line.to_string(), config.get_application(), append);
-        let message = crate::message::Message::Data(message);
-        if tx.is_closed() {
-            break;
-        }
-        match tx.send(message).await{
-            Ok(_) => {}
-            Err(e) => {
-                error!("Error sending message: {}", e);
-                break;
-            }
-        }
-        debug!("{}", line);
-        *last_line = line.to_string();
+    if let Err(e) = tx.send(message).await {
+        error!("Error sending message: {}", e);
     }
-}
-
-pub(crate) fn match_file_name(file_name: &str, regex: &str) -> bool {
-    let re = match regex::Regex::new(regex) {
-        Ok(re) => re,
-        Err(e) => {
-            error!("Error creating regex: {}", e);
-            return false
-        }
-    };
-    re.is_match(file_name)
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::message::{FileIdentity, FileOffset};
+
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct SpoolState {
+    /// By path, state files from before the identity was kept have `offsets` instead, which are ignored
+    #[serde(default)]
+    files: BTreeMap<String, SpooledOffset>
+}
+
+/// How far the file at a path was shipped, and which file that was.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+struct SpooledOffset {
+    identity: FileIdentity,
+    offset: u64
+}
+
+/// Remembers how far each tailed file was shipped to the server, so a reconnect resumes where it left off.
+/// Offsets are kept in memory and, when a state file is configured, persisted to it.
+/// Lines sent but not yet acknowledged only move the in memory offset, a restart sends them again.
+pub struct Spool {
+    state_file: Option<PathBuf>,
+    state: SpoolState,
+    sent: BTreeMap<String, SpooledOffset>,
+    dirty: bool,
+    last_persisted: Instant
+}
+
+impl From<&FileOffset> for SpooledOffset {
+    fn from(file_offset: &FileOffset) -> Self {
+        Self { identity: file_offset.identity, offset: file_offset.offset }
+    }
+}
+
+impl Spool {
+    pub fn load(state_file: Option<String>) -> Self {
+        let state_file = state_file.map(PathBuf::from);
+        let state = match &state_file {
+            Some(path) if path.exists() => match std::fs::read_to_string(path) {
+                Ok(state) => serde_json::from_str(&state).unwrap_or_else(|err| {
+                    error!("Error parsing spool state file {:?}: {}", path, err);
+                    SpoolState::default()
+                }),
+                Err(err) => {
+                    error!("Error reading spool state file {:?}: {}", path, err);
+                    SpoolState::default()
+                }
+            },
+            _ => SpoolState::default(),
+        };
+        info!("Loaded {} spooled file offsets", state.files.len());
+        Self { state_file, state, sent: BTreeMap::new(), dirty: false, last_persisted: Instant::now() }
+    }
+
+    /// Where to resume the file `identity` at `path`. The start when another file replaced the one spooled for the path,
+    /// e.g. it was rotated while disconnected, since everything in the replacement is new.
+    pub fn offset(&self, path: &Path, identity: FileIdentity) -> Option<u64> {
+        let path = path.to_string_lossy().to_string();
+        let mut spooled = [self.sent.get(&path), self.state.files.get(&path)].into_iter().flatten().peekable();
+        spooled.peek()?;
+        match spooled.filter(|spooled| spooled.identity == identity).map(|spooled| spooled.offset).max() {
+            Some(offset) => Some(offset),
+            None => {
+                info!("File {:?} was replaced since it was spooled, reading it from the start", path);
+                Some(0)
+            },
+        }
+    }
+
+    /// Records lines as sent, they are only committed once acknowledged.
+    pub fn sent(&mut self, file_offset: &FileOffset) {
+        self.sent.insert(file_offset.path.to_string_lossy().to_string(), SpooledOffset::from(file_offset));
+    }
+
+    pub fn commit(&mut self, file_offset: &FileOffset) {
+        self.state.files.insert(file_offset.path.to_string_lossy().to_string(), SpooledOffset::from(file_offset));
+        self.dirty = true;
+        if self.last_persisted.elapsed() >= PERSIST_INTERVAL {
+            self.persist();
+        }
+    }
+
+    /// Writes the offsets to the state file if they changed since the last write.
+    pub fn persist(&mut self) {
+        let path = match &self.state_file {
+            Some(path) if self.dirty => path,
+            _ => return,
+        };
+        let state = match serde_json::to_string(&self.state) {
+            Ok(state) => state,
+            Err(err) => {
+                error!("Error serializing spool state: {}", err);
+                return;
+            }
+        };
+        // Write to a temporary file first so a crash never leaves a half written state file behind
+        let temp = path.with_extension("tmp");
+        if let Err(err) = std::fs::write(&temp, state).and_then(|_| std::fs::rename(&temp, path)) {
+            error!("Error writing spool state file {:?}: {}", path, err);
+            return;
+        }
+        self.dirty = false;
+        self.last_persisted = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::client::testing::temp_dir;
+
+    fn file_offset(path: &Path, offset: u64) -> FileOffset {
+        FileOffset { path: path.to_path_buf(), identity: FileIdentity::of(&fs::metadata(path).unwrap()), offset }
+    }
+
+    #[test]
+    fn resumes_after_sent_lines_but_only_persists_acknowledged_ones() {
+        let dir = temp_dir("spool-sent");
+        let (log, state) = (dir.join("app.log"), dir.join("spool.json").to_string_lossy().to_string());
+        fs::write(&log, "first\nsecond\nthird\n").unwrap();
+        let identity = file_offset(&log, 0).identity;
+
+        let mut spool = Spool::load(Some(state.clone()));
+        assert_eq!(spool.offset(&log, identity), None);
+        spool.commit(&file_offset(&log, 6));
+        spool.sent(&file_offset(&log, 13));
+        // A reconnect goes on after what was sent, a restart only after what the server acknowledged
+        assert_eq!(spool.offset(&log, identity), Some(13));
+        spool.persist();
+        assert_eq!(Spool::load(Some(state.clone())).offset(&log, identity), Some(6));
+
+        // Without a state file the offsets only last as long as the process
+        let mut spool = Spool::load(None);
+        spool.commit(&file_offset(&log, 19));
+        spool.persist();
+        assert_eq!(spool.offset(&log, identity), Some(19));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn starts_over_on_a_file_rotated_between_save_and_load() {
+        let dir = temp_dir("spool");
+        let (log, state) = (dir.join("app.log"), dir.join("spool.json"));
+        fs::write(&log, "first\nsecond\n").unwrap();
+        let shipped = file_offset(&log, 13);
+
+        let mut spool = Spool::load(Some(state.to_string_lossy().to_string()));
+        spool.commit(&shipped);
+        spool.persist();
+
+        // The rotated file is kept, so the replacement can't get its inode
+        fs::rename(&log, dir.join("app.log.1")).unwrap();
+        fs::write(&log, "third\nfourth\nfifth\n").unwrap();
+        let replacement = file_offset(&log, 0);
+
+        let spool = Spool::load(Some(state.to_string_lossy().to_string()));
+        assert_eq!(spool.offset(&log, shipped.identity), Some(13));
+        assert_eq!(spool.offset(&log, replacement.identity), Some(0));
+        assert_eq!(spool.offset(&PathBuf::from("other.log"), replacement.identity), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::SeekFrom;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+const CHUNK_SIZE: u64 = 2_u64.pow(16);
+
+/// Where to start reading a file when there's no spooled offset to resume from.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StartFrom {
+    Beginning,
+    /// Only what is written after tailing starts
+    #[default]
+    End,
+    /// The last N lines
+    LastLines(u64),
+    /// The last N bytes
+    LastBytes(u64)
+}
+
+impl StartFrom {
+    /// Offset to start reading a file of the given length at.
+    pub async fn offset<R: AsyncRead + AsyncSeek + Unpin>(&self, reader: &mut R, length: u64) -> std::io::Result<u64> {
+        match self {
+            StartFrom::Beginning => Ok(0),
+            StartFrom::End => Ok(length),
+            StartFrom::LastBytes(bytes) => Ok(length.saturating_sub(*bytes)),
+            StartFrom::LastLines(lines) => last_lines_offset(reader, length, *lines).await,
+        }
+    }
+}
+
+/// Finds where the last `lines` lines start by reading backwards from the end in chunks,
+/// so only the tail of a large file is ever read.
+async fn last_lines_offset<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R, length: u64, lines: u64) -> std::io::Result<u64> {
+    if lines == 0 || length == 0 {
+        return Ok(length);
+    }
+
+    let mut buffer = vec![0; CHUNK_SIZE as usize];
+    let mut end = length;
+    let mut newlines = 0;
+    // The newline ending the last line doesn't start a new one
+    let mut skip_last = true;
+
+    while end > 0 {
+        let start = end.saturating_sub(CHUNK_SIZE);
+        let chunk = &mut buffer[..(end - start) as usize];
+        reader.seek(SeekFrom::Start(start)).await?;
+        reader.read_exact(chunk).await?;
+
+        for (index, byte) in chunk.iter().enumerate().rev() {
+            if *byte != b'\n' {
+                skip_last = false;
+                continue;
+            }
+            if skip_last {
+                skip_last = false;
+                continue;
+            }
+            newlines += 1;
+            if newlines == lines {
+                return Ok(start + index as u64 + 1);
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    async fn offset(content: &[u8], start_from: StartFrom) -> u64 {
+        let mut reader = Cursor::new(content.to_vec());
+        start_from.offset(&mut reader, content.len() as u64).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn starts_at_the_ends() {
+        assert_eq!(offset(b"a\nb\n", StartFrom::Beginning).await, 0);
+        assert_eq!(offset(b"a\nb\n", StartFrom::End).await, 4);
+    }
+
+    #[tokio::test]
+    async fn starts_at_last_bytes() {
+        assert_eq!(offset(b"a\nb\n", StartFrom::LastBytes(2)).await, 2);
+        assert_eq!(offset(b"a\nb\n", StartFrom::LastBytes(10)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn starts_at_last_lines() {
+        assert_eq!(offset(b"one\ntwo\nthree\n", StartFrom::LastLines(1)).await, 8);
+        assert_eq!(offset(b"one\ntwo\nthree\n", StartFrom::LastLines(2)).await, 4);
+        assert_eq!(offset(b"one\ntwo\nthree\n", StartFrom::LastLines(3)).await, 0);
+        assert_eq!(offset(b"one\ntwo\nthree\n", StartFrom::LastLines(5)).await, 0);
+        assert_eq!(offset(b"one\ntwo\nthree\n", StartFrom::LastLines(0)).await, 14);
+    }
+
+    #[tokio::test]
+    async fn counts_an_unfinished_last_line() {
+        assert_eq!(offset(b"one\ntwo\nthr", StartFrom::LastLines(1)).await, 8);
+        assert_eq!(offset(b"one\ntwo\nthr", StartFrom::LastLines(2)).await, 4);
+    }
+
+    #[tokio::test]
+    async fn finds_last_lines_across_chunks() {
+        let line = vec![b'x'; CHUNK_SIZE as usize - 1];
+        let mut content = vec![];
+        for _ in 0..4 {
+            content.extend_from_slice(&line);
+            content.push(b'\n');
+        }
+        assert_eq!(offset(&content, StartFrom::LastLines(2)).await, 2 * CHUNK_SIZE);
+        assert_eq!(offset(&content, StartFrom::LastLines(4)).await, 0);
+    }
+}
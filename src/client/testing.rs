@@ -0,0 +1,30 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use serde_json::{json, Value};
+
+use super::configuration::LogConfiguration;
+
+/// Empty directory of its own for a test, named after it as tests run in parallel.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("webtail-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Config tailing `app.log` in `dir` for the application `app`, with `settings` set on top.
+pub fn configuration(dir: &Path, settings: Value) -> LogConfiguration {
+    let mut configuration = json!({
+        "app_name": {"SinglePod": "app"},
+        "log_file_dir": dir,
+        "log_file_name_regex": r"^app\.log$",
+        "server_host": "localhost",
+        "server_port": 8080,
+        "server_path": "ws",
+        "channel_buffer": 10
+    });
+    if let (Some(configuration), Value::Object(settings)) = (configuration.as_object_mut(), settings) {
+        configuration.extend(settings);
+    }
+    serde_json::from_value(configuration).unwrap()
+}
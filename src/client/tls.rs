@@ -0,0 +1,161 @@
+use std::sync::Arc;
+
+use log::warn;
+use rustls::{
+    client::{danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, WebPkiServerVerifier},
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::Connector;
+
+/// Connecting to the server over TLS (wss). Paths are PEM files.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Tls {
+    /// CA certificates to trust instead of the system's
+    ca_bundle: Option<String>,
+    /// Certificate chain and key to authenticate the client with, for mutual TLS
+    client_certificate: Option<String>,
+    client_key: Option<String>,
+    /// Accept certificates issued for another host name, only meant for test environments
+    skip_hostname_verification: bool
+}
+
+impl Tls {
+    /// Builds the TLS connector, the files are read again on every connection so renewed certificates are picked up.
+    pub fn connector(&self) -> Result<Connector, String> {
+        let provider = Arc::new(ring::default_provider());
+        let roots = Arc::new(self.roots()?);
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Error setting up TLS: {}", e))?;
+        let builder = match self.skip_hostname_verification {
+            true => {
+                warn!("Not verifying the host name of the server certificate");
+                let verifier = WebPkiServerVerifier::builder_with_provider(roots, provider).build()
+                    .map_err(|e| format!("Error setting up certificate verification: {}", e))?;
+                builder.dangerous().with_custom_certificate_verifier(Arc::new(AnyHostName { verifier }))
+            },
+            false => builder.with_root_certificates(roots),
+        };
+
+        let config = match (&self.client_certificate, &self.client_key) {
+            (Some(certificate), Some(key)) => {
+                let chain = CertificateDer::pem_file_iter(certificate)
+                    .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| format!("Error reading client certificate {}: {}", certificate, e))?;
+                let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("Error reading client key {}: {}", key, e))?;
+                builder.with_client_auth_cert(chain, key).map_err(|e| format!("Error using client certificate: {}", e))?
+            },
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("client_certificate and client_key have to be configured together".to_string()),
+        };
+        Ok(Connector::Rustls(Arc::new(config)))
+    }
+
+    fn roots(&self) -> Result<RootCertStore, String> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_bundle {
+            Some(ca_bundle) => {
+                let certificates = CertificateDer::pem_file_iter(ca_bundle)
+                    .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| format!("Error reading CA bundle {}: {}", ca_bundle, e))?;
+                for certificate in certificates {
+                    roots.add(certificate).map_err(|e| format!("Error adding certificate from CA bundle {}: {}", ca_bundle, e))?;
+                }
+            },
+            None => {
+                let native = rustls_native_certs::load_native_certs();
+                for e in native.errors {
+                    warn!("Error loading system CA certificates: {}", e);
+                }
+                roots.add_parsable_certificates(native.certs);
+            }
+        }
+        Ok(roots)
+    }
+}
+
+/// Verifies the server certificate like always, except for the host name it was issued for.
+#[derive(Debug)]
+struct AnyHostName {
+    verifier: Arc<WebPkiServerVerifier>
+}
+
+impl ServerCertVerifier for AnyHostName {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>], server_name: &ServerName<'_>, ocsp_response: &[u8], now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        match self.verifier.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. })) => Ok(ServerCertVerified::assertion()),
+            verified => verified,
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A test CA and a certificate it issued for localhost, usable by servers and clients.
+    fn testdata(file: &str) -> String {
+        format!("{}/src/client/testdata/{}", env!("CARGO_MANIFEST_DIR"), file)
+    }
+
+    fn tls(ca_bundle: Option<&str>, client_certificate: Option<&str>, client_key: Option<&str>) -> Tls {
+        Tls {
+            ca_bundle: ca_bundle.map(testdata),
+            client_certificate: client_certificate.map(testdata),
+            client_key: client_key.map(testdata),
+            skip_hostname_verification: false,
+        }
+    }
+
+    fn any_host_name(ca_bundle: &str) -> AnyHostName {
+        let roots = Arc::new(tls(Some(ca_bundle), None, None).roots().unwrap());
+        AnyHostName { verifier: WebPkiServerVerifier::builder_with_provider(roots, Arc::new(ring::default_provider())).build().unwrap() }
+    }
+
+    #[test]
+    fn connects_with_a_ca_bundle_and_client_certificate() {
+        assert_eq!(tls(Some("ca.pem"), None, None).roots().unwrap().len(), 1);
+        assert!(tls(Some("ca.pem"), Some("localhost.pem"), Some("localhost.key")).connector().is_ok());
+        assert!(Tls { skip_hostname_verification: true, ..tls(Some("ca.pem"), None, None) }.connector().is_ok());
+    }
+
+    #[test]
+    fn tells_what_it_could_not_read() {
+        let missing = tls(Some("missing.pem"), None, None).connector().err().unwrap();
+        assert!(missing.contains("missing.pem"), "{}", missing);
+        let missing = tls(Some("ca.pem"), Some("localhost.pem"), Some("missing.key")).connector().err().unwrap();
+        assert!(missing.contains("missing.key"), "{}", missing);
+        assert_eq!(tls(Some("ca.pem"), Some("localhost.pem"), None).connector().err().unwrap(), "client_certificate and client_key have to be configured together");
+    }
+
+    #[test]
+    fn only_skips_the_host_name() {
+        let certificate = CertificateDer::from_pem_file(testdata("localhost.pem")).unwrap();
+        let verify = |verifier: &dyn ServerCertVerifier, host: &'static str| verifier.verify_server_cert(&certificate, &[], &ServerName::try_from(host).unwrap(), &[], UnixTime::now());
+
+        let trusted = any_host_name("ca.pem");
+        assert!(verify(&*trusted.verifier, "localhost").is_ok());
+        assert!(verify(&*trusted.verifier, "webtail.example").is_err());
+        assert!(verify(&trusted, "webtail.example").is_ok());
+        // Issued by a CA that isn't trusted
+        let untrusted = any_host_name("localhost.pem");
+        assert!(matches!(verify(&untrusted, "localhost"), Err(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer))));
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt::{self, Display, Formatter};
+
 use borsh::{BorshDeserialize, BorshSerialize};
 use serde::{Deserialize, Serialize};
 
@@ -30,8 +32,8 @@ impl Applicatiton {
     }
 }
 
-impl ToString for Applicatiton {
-    fn to_string(&self) -> String {
-        serde_json::to_string(self).unwrap()
+impl Display for Applicatiton {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| fmt::Error)?)
     }
 }
\ No newline at end of file
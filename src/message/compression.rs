@@ -0,0 +1,43 @@
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+/// Messages smaller than this are sent as they are, compressing them gains next to nothing.
+pub const COMPRESSION_THRESHOLD: usize = 256;
+/// Most bytes a compressed message may inflate to, anything bigger is taken as broken.
+const MAX_DECOMPRESSED_BYTES: u64 = 2_u64.pow(24);
+
+pub fn compress(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+pub fn decompress(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    DeflateDecoder::new(bytes).take(MAX_DECOMPRESSED_BYTES + 1).read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_BYTES {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "compressed message too large"));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_what_it_compressed() {
+        let bytes = "a repeated log line\n".repeat(100).into_bytes();
+        let compressed = compress(&bytes).unwrap();
+        assert!(compressed.len() < bytes.len());
+        assert_eq!(decompress(&compressed).unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_messages_inflating_past_the_limit() {
+        let compressed = compress(&vec![0; MAX_DECOMPRESSED_BYTES as usize + 1]).unwrap();
+        assert_eq!(decompress(&compressed).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(decompress(&compress(&vec![0; MAX_DECOMPRESSED_BYTES as usize]).unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{collections::BTreeSet, fmt};
+
+use serde::{Deserialize, Serialize};
+
+/// Request header the client announces its protocol version in, the server answers with the agreed one.
+pub const PROTOCOL_VERSION_HEADER: &str = "webtail-protocol-version";
+/// Request header the client announces its features in, the server answers with the agreed ones.
+pub const FEATURES_HEADER: &str = "webtail-features";
+/// Request header identifying a client across its reconnects, so the server can drop frames it resends.
+pub const CLIENT_ID_HEADER: &str = "webtail-client-id";
+
+/// Request header telling the server what the client does with lines while paused.
+pub const PAUSE_POLICY_HEADER: &str = "webtail-pause-policy";
+
+/// Protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// First protocol version that knows about the files lines come from. Data messages may carry the name of their file,
+/// and clients tell about truncated, rotated, recreated and newer files and about history they finished sending.
+pub const FILE_EVENTS_VERSION: u32 = 2;
+/// Oldest protocol version this build still speaks. Clients that don't announce a version speak version 1.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional parts of the protocol, only used when both sides support them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Feature {
+    Compression,
+    Batching,
+    Ack
+}
+
+/// Features this build supports.
+const SUPPORTED_FEATURES: &[Feature] = &[Feature::Compression, Feature::Batching, Feature::Ack];
+
+impl Feature {
+    fn name(&self) -> &'static str {
+        match self {
+            Feature::Compression => "compression",
+            Feature::Batching => "batching",
+            Feature::Ack => "ack",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "compression" => Some(Feature::Compression),
+            "batching" => Some(Feature::Batching),
+            "ack" => Some(Feature::Ack),
+            _ => None,
+        }
+    }
+}
+
+/// What a client does with lines read while no one is watching its application.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PausePolicy {
+    /// Drop them, the server pauses the client and discards what it already sent
+    #[default]
+    Drop,
+    /// Keep the last N lines on the client and send them on resume
+    Buffer(usize),
+    /// Keep sending, so the server's history is warm when someone starts watching
+    Forward
+}
+
+impl PausePolicy {
+    pub fn header(&self) -> &'static str {
+        match self {
+            PausePolicy::Drop => "drop",
+            PausePolicy::Buffer(_) => "buffer",
+            PausePolicy::Forward => "forward",
+        }
+    }
+
+    /// The server only needs to know the kind of policy, clients from before policies drop lines.
+    pub fn from_header(header: Option<&str>) -> Self {
+        match header.map(str::trim) {
+            Some("buffer") => PausePolicy::Buffer(0),
+            Some("forward") => PausePolicy::Forward,
+            _ => PausePolicy::Drop,
+        }
+    }
+}
+
+/// Protocol version and features one side of a connection speaks, or both sides agreed on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u32,
+    pub features: BTreeSet<Feature>
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self { version: PROTOCOL_VERSION, features: SUPPORTED_FEATURES.iter().copied().collect() }
+    }
+}
+
+impl Handshake {
+    /// What a peer built before the handshake existed speaks.
+    pub fn legacy() -> Self {
+        Self { version: 1, features: BTreeSet::new() }
+    }
+
+    /// Reads the peer's handshake from its header values, a peer without a version header is a legacy one.
+    /// Features this build doesn't know are left out, they can't be agreed on anyway.
+    pub fn from_headers(version: Option<&str>, features: Option<&str>) -> Result<Self, String> {
+        let version = match version {
+            Some(version) => version.trim().parse().map_err(|_| format!("invalid protocol version: {}", version))?,
+            None => return Ok(Self::legacy()),
+        };
+        let features = features.unwrap_or_default()
+            .split(',')
+            .filter_map(|feature| Feature::parse(feature.trim()))
+            .collect();
+        Ok(Self { version, features })
+    }
+
+    pub fn version_header(&self) -> String {
+        self.version.to_string()
+    }
+
+    pub fn features_header(&self) -> String {
+        self.features.iter().map(Feature::name).collect::<Vec<_>>().join(", ")
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// What both sides speak, or why they can't talk to each other.
+    pub fn negotiate(&self, peer: &Handshake) -> Result<Handshake, String> {
+        let version = self.version.min(peer.version);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(format!("protocol version {} is not supported, versions {} to {} are", peer.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+        }
+        let features = self.features.intersection(&peer.features).copied().collect();
+        Ok(Handshake { version, features })
+    }
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "protocol version {} with features [{}]", self.version, self.features_header())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::SystemMessages;
+
+    fn handshake(version: u32, features: &[Feature]) -> Handshake {
+        Handshake { version, features: features.iter().copied().collect() }
+    }
+
+    #[test]
+    fn reads_headers() {
+        assert_eq!(Handshake::from_headers(None, Some("ack")), Ok(Handshake::legacy()));
+        assert_eq!(Handshake::from_headers(Some("2"), Some("ack, batching,unknown")), Ok(handshake(2, &[Feature::Ack, Feature::Batching])));
+        assert_eq!(Handshake::from_headers(Some("2"), None), Ok(handshake(2, &[])));
+        assert!(Handshake::from_headers(Some("two"), None).is_err());
+    }
+
+    #[test]
+    fn writes_headers() {
+        let handshake = handshake(1, &[Feature::Ack, Feature::Compression]);
+        assert_eq!(Handshake::from_headers(Some(&handshake.version_header()), Some(&handshake.features_header())), Ok(handshake));
+    }
+
+    #[test]
+    fn agrees_on_common_features_and_older_version() {
+        let ours = handshake(PROTOCOL_VERSION, &[Feature::Batching, Feature::Ack]);
+        let theirs = handshake(PROTOCOL_VERSION + 1, &[Feature::Ack, Feature::Compression]);
+        assert_eq!(ours.negotiate(&theirs), Ok(handshake(PROTOCOL_VERSION, &[Feature::Ack])));
+    }
+
+    #[test]
+    fn speaks_the_first_version_with_legacy_peers() {
+        let agreed = Handshake::default().negotiate(&Handshake::legacy()).unwrap();
+        assert_eq!(agreed, handshake(1, &[]));
+        assert!(agreed.version < FILE_EVENTS_VERSION);
+        assert!(SystemMessages::FileFound.protocol_version() <= agreed.version);
+        for message in [SystemMessages::FileTruncated, SystemMessages::FileRotated, SystemMessages::HistoryFinished { file: "app.log.1".to_string() }] {
+            assert!(message.protocol_version() > agreed.version);
+        }
+    }
+
+    #[test]
+    fn tells_the_kind_of_pause_policy() {
+        for policy in [PausePolicy::Drop, PausePolicy::Buffer(0), PausePolicy::Forward] {
+            assert_eq!(PausePolicy::from_header(Some(policy.header())), policy);
+        }
+        // How many lines a client buffers is its own business
+        assert_eq!(PausePolicy::from_header(Some(PausePolicy::Buffer(100).header())), PausePolicy::Buffer(0));
+        assert_eq!(PausePolicy::from_header(None), PausePolicy::Drop);
+        assert_eq!(PausePolicy::from_header(Some("unknown")), PausePolicy::Drop);
+    }
+
+    #[test]
+    fn rejects_versions_that_are_too_old() {
+        assert!(Handshake::default().negotiate(&handshake(MIN_PROTOCOL_VERSION - 1, &[])).is_err());
+    }
+}
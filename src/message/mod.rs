@@ -1,9 +1,14 @@
+use std::{fs, path::PathBuf};
+
 use borsh::{BorshDeserialize, BorshSerialize};
 use chrono::{DateTime, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 
 use crate::Applicatiton;
 
+pub mod compression;
+pub mod handshake;
+
 
 #[derive(Debug, Serialize, Deserialize, BorshSerialize, BorshDeserialize, PartialEq, Eq, Clone)]
 pub enum SystemMessages {
@@ -14,7 +19,69 @@ pub enum SystemMessages {
     Start,
     Stop,
     Pause,
-    Resume
+    Resume,
+    /// Events with ids in `from..to` were evicted before a resuming subscriber could get them
+    Gap { from: u64, to: u64 },
+    FileTruncated,
+    FileRotated,
+    FileRecreated,
+    NewerFileFound,
+    /// Asks a client to send a rotated file, optionally only the lines timestamped within `from..=to` (unix nanos)
+    ReadHistory { file: String, from: Option<i64>, to: Option<i64> },
+    /// A client finished sending a file asked for with `ReadHistory`
+    HistoryFinished { file: String },
+    /// Tells a client every frame up to and including `sequence` was handled, only sent when acks were agreed on
+    Ack { sequence: u64 },
+    /// Tells a subscriber that fell behind how many messages it skipped
+    Lagged { skipped: u64 }
+}
+
+impl SystemMessages {
+    /// Protocol version the message was added in, servers speaking an older one can't read it.
+    pub fn protocol_version(&self) -> u32 {
+        match self {
+            SystemMessages::FileTruncated
+            | SystemMessages::FileRotated
+            | SystemMessages::FileRecreated
+            | SystemMessages::NewerFileFound
+            | SystemMessages::HistoryFinished { .. } => handshake::FILE_EVENTS_VERSION,
+            _ => handshake::MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// Identifies a file independently of its path, so a rotated file can be told apart from its replacement.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileIdentity {
+    device: u64,
+    inode: u64
+}
+
+impl FileIdentity {
+    #[cfg(unix)]
+    pub fn of(metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self { device: metadata.dev(), inode: metadata.ino() }
+    }
+
+    // Without inodes the modification time of a fresh file is the closest stand in
+    #[cfg(not(unix))]
+    pub fn of(metadata: &fs::Metadata) -> Self {
+        let created = metadata.created().or_else(|_| metadata.modified()).ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default();
+        Self { device: 0, inode: created }
+    }
+}
+
+/// Position in a tailed file right after a row, only known on the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOffset {
+    pub path: PathBuf,
+    /// The file the offset is in, whatever is at `path` by the time it's resumed from
+    pub identity: FileIdentity,
+    pub offset: u64
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -24,7 +91,14 @@ pub struct DataMessage {
     row: String,
     application: Applicatiton,
     replace_last_row: bool,
-    timestamp: NaiveDateTime
+    timestamp: NaiveDateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    /// Read from an older file on request rather than tailed live
+    #[serde(default)]
+    historical: bool,
+    #[serde(skip)]
+    file_offset: Option<FileOffset>
 }
 
 impl From<BinaryDataMessage> for DataMessage {
@@ -35,6 +109,9 @@ impl From<BinaryDataMessage> for DataMessage {
             application: value.application,
             replace_last_row: value.replace_last_row,
             timestamp: DateTime::from_timestamp_nanos(value.timestamp).naive_utc(),
+            file_name: None,
+            historical: false,
+            file_offset: None,
         }
     }
 }
@@ -60,6 +137,38 @@ impl From<DataMessage> for BinaryDataMessage {
     }
 }
 
+/// Data message in a batch, without what the connection already tells, like the application.
+#[derive(Debug, BorshSerialize, BorshDeserialize)]
+pub struct BatchedDataMessage {
+    row: String,
+    replace_last_row: bool,
+    timestamp: i64,
+    file_name: Option<String>,
+    historical: bool
+}
+
+impl From<DataMessage> for BatchedDataMessage {
+    fn from(value: DataMessage) -> Self {
+        Self {
+            row: value.row,
+            replace_last_row: value.replace_last_row,
+            timestamp: value.timestamp.and_utc().timestamp_nanos_opt().expect("Out of bound timestamp for unix time"),
+            file_name: value.file_name,
+            historical: value.historical,
+        }
+    }
+}
+
+impl BatchedDataMessage {
+    fn into_data(self, application: &Applicatiton) -> DataMessage {
+        let mut data = DataMessage::new(self.row, application.clone(), self.replace_last_row);
+        data.timestamp = DateTime::from_timestamp_nanos(self.timestamp).naive_utc();
+        data.file_name = self.file_name;
+        data.historical = self.historical;
+        data
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SystemMessage {
     #[serde(rename = "type")]
@@ -101,12 +210,48 @@ impl From<SystemMessage> for BinarySystemMessage {
 
 impl DataMessage {
     pub fn new(row: String, application: Applicatiton, replace_last_row: bool) -> Self {
-        Self { message_type: "Data".to_string(), row, application, replace_last_row, timestamp: chrono::Utc::now().naive_utc() }
+        Self { message_type: "Data".to_string(), row, application, replace_last_row, timestamp: chrono::Utc::now().naive_utc(), file_name: None, historical: false, file_offset: None }
+    }
+
+    pub fn with_file_name(mut self, file_name: String) -> Self {
+        self.file_name = Some(file_name);
+        self
+    }
+
+    pub fn without_file_name(mut self) -> Self {
+        self.file_name = None;
+        self
+    }
+
+    pub fn historical(mut self, timestamp: Option<NaiveDateTime>) -> Self {
+        self.historical = true;
+        if let Some(timestamp) = timestamp {
+            self.timestamp = timestamp;
+        }
+        self
+    }
+
+    pub fn is_historical(&self) -> bool {
+        self.historical
+    }
+
+    pub fn with_file_offset(mut self, file_offset: FileOffset) -> Self {
+        self.file_offset = Some(file_offset);
+        self
     }
 
     pub fn row(&self) -> &str {
         &self.row
     }
+
+    /// Rough size of the message once serialized in a batch.
+    pub fn batched_size(&self) -> usize {
+        self.row.len() + self.file_name.as_ref().map(String::len).unwrap_or(0) + 24
+    }
+
+    pub fn file_offset(&self) -> Option<&FileOffset> {
+        self.file_offset.as_ref()
+    }
 }
 
 impl SystemMessage {
@@ -126,16 +271,6 @@ pub enum Message {
     ClientDisconnect
 }
 
-impl From<BinaryMessage> for Message {
-    fn from(value: BinaryMessage) -> Self {
-        match value {
-            BinaryMessage::Data(binary_data_message) => Message::Data(DataMessage::from(binary_data_message)),
-            BinaryMessage::System(binary_system_message) => Message::System(SystemMessage::from(binary_system_message)),
-            BinaryMessage::ClientDisconnect => Message::ClientDisconnect,
-        }
-    }
-}
-
 impl Message {
     pub fn data(&self) -> Option<&DataMessage> {
         match self {
@@ -156,13 +291,47 @@ impl Message {
 pub enum BinaryMessage {
     Data(BinaryDataMessage),
     System(BinarySystemMessage),
-    ClientDisconnect
+    ClientDisconnect,
+    /// Data along with the name of the file it was read from, only sent when protocol version 2 or later was agreed on
+    FileData(BinaryDataMessage, String),
+    /// Data read from an older file on request, along with that file's name
+    HistoricalData(BinaryDataMessage, String),
+    /// Several data messages of the application the connection was opened for, only sent when batching was agreed on
+    Batch(Vec<BatchedDataMessage>),
+    /// Deflate compressed borsh of another message, only sent when compression was agreed on
+    Compressed(Vec<u8>),
+    /// Borsh of another message with the client's sequence number to acknowledge it by, only sent when acks were agreed on
+    Sequenced(u64, Vec<u8>)
+}
+
+impl BinaryMessage {
+    /// Messages carried by this one, `application` being the one the connection was opened for.
+    pub fn into_messages(self, application: &Applicatiton) -> std::io::Result<Vec<Message>> {
+        let message = match self {
+            BinaryMessage::Data(binary_data_message) => Message::Data(DataMessage::from(binary_data_message)),
+            BinaryMessage::System(binary_system_message) => Message::System(SystemMessage::from(binary_system_message)),
+            BinaryMessage::ClientDisconnect => Message::ClientDisconnect,
+            BinaryMessage::FileData(binary_data_message, file_name) => Message::Data(DataMessage::from(binary_data_message).with_file_name(file_name)),
+            BinaryMessage::HistoricalData(binary_data_message, file_name) => Message::Data(DataMessage::from(binary_data_message).with_file_name(file_name).historical(None)),
+            BinaryMessage::Batch(batch) => return Ok(batch.into_iter().map(|data| Message::Data(data.into_data(application))).collect()),
+            BinaryMessage::Compressed(bytes) => {
+                let binary_message: BinaryMessage = borsh::from_slice(&compression::decompress(&bytes)?)?;
+                return binary_message.into_messages(application);
+            },
+            BinaryMessage::Sequenced(_, bytes) => return borsh::from_slice::<BinaryMessage>(&bytes)?.into_messages(application),
+        };
+        Ok(vec![message])
+    }
 }
 
 impl From<Message> for BinaryMessage {
     fn from(value: Message) -> Self {
         match value {
-            Message::Data(data_message) => BinaryMessage::Data(BinaryDataMessage::from(data_message)),
+            Message::Data(mut data_message) => match (data_message.file_name.take(), data_message.historical) {
+                (file_name, true) => BinaryMessage::HistoricalData(BinaryDataMessage::from(data_message), file_name.unwrap_or_default()),
+                (Some(file_name), false) => BinaryMessage::FileData(BinaryDataMessage::from(data_message), file_name),
+                (None, false) => BinaryMessage::Data(BinaryDataMessage::from(data_message)),
+            },
             Message::System(system_message) => BinaryMessage::System(BinarySystemMessage::from(system_message)),
             Message::ClientDisconnect => BinaryMessage::ClientDisconnect,
         }
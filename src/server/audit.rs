@@ -0,0 +1,329 @@
+use std::{collections::VecDeque, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::{mpsc, Arc, Mutex}, thread::{self, JoinHandle}, time::Instant};
+
+use chrono::NaiveDateTime;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{server::{pattern::Pattern, sessions::CurrentUser}, Applicatiton};
+
+/// Most records a query returns when it doesn't ask for fewer.
+const DEFAULT_QUERY_LIMIT: usize = 1000;
+
+/// What an audit record is about.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A UI user started watching an application's SSE stream
+    StreamOpened,
+    StreamClosed,
+    /// A client connected to publish an application
+    ClientRegistered,
+    ClientDisconnected,
+    Login,
+    LoginFailed
+}
+
+/// One line of the audit log.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    timestamp: NaiveDateTime,
+    event: AuditEvent,
+    /// The UI user, or the fingerprint of the token a client authenticated with
+    user: Option<String>,
+    peer: Option<String>,
+    application: Option<Applicatiton>,
+    /// How long the stream or connection lasted, on the records of it closing
+    duration_ms: Option<u64>,
+    /// How the user logged in, or why they couldn't
+    detail: Option<String>
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent, user: Option<String>, peer: Option<String>) -> Self {
+        Self { timestamp: chrono::Utc::now().naive_utc(), event, user, peer, application: None, duration_ms: None, detail: None }
+    }
+
+    pub fn with_application(mut self, application: Applicatiton) -> Self {
+        self.application = Some(application);
+        self
+    }
+
+    pub fn with_detail(mut self, detail: String) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+/// Filters of `GET /api/audit`, all optional. `application` may have `*` wildcards, `from` and `to` are UTC.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    user: Option<String>,
+    application: Option<Pattern>,
+    event: Option<AuditEvent>,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    /// Only the latest this many records
+    limit: Option<usize>
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        self.user.as_ref().is_none_or(|user| record.user.as_ref() == Some(user))
+            && self.application.as_ref().is_none_or(|pattern| record.application.as_ref().is_some_and(|application| pattern.matches(&application.name())))
+            && self.event.is_none_or(|event| record.event == event)
+            && self.from.is_none_or(|from| record.timestamp >= from)
+            && self.to.is_none_or(|to| record.timestamp <= to)
+    }
+}
+
+/// The audit log file, rotated to `<file>.1`, `<file>.2` ... once it grows over `max_bytes`.
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    /// How many rotated files to keep
+    keep: usize
+}
+
+impl AuditFile {
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn append(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.keep).rev() {
+            let rotated = self.rotated(index);
+            if rotated.exists() {
+                fs::rename(&rotated, self.rotated(index + 1))?;
+            }
+        }
+        match self.keep {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, self.rotated(1))?,
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// The files oldest first.
+    fn files(&self) -> Vec<PathBuf> {
+        (1..=self.keep).rev().map(|index| self.rotated(index)).chain([self.path.clone()]).collect()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Records of who watched and published which application and when, appended as JSON lines to a rotating file.
+/// Without a file nothing is recorded.
+pub struct AuditLog {
+    file: Option<Arc<Mutex<AuditFile>>>,
+    /// Records waiting for the writer thread, so recording never waits on the disk
+    records: Option<mpsc::Sender<AuditRecord>>,
+    writer: Option<JoinHandle<()>>,
+    /// Role of the users allowed to query the log
+    admin_role: String
+}
+
+fn write(file: &Mutex<AuditFile>, record: AuditRecord) {
+    let mut line = match serde_json::to_vec(&record) {
+        Ok(line) => line,
+        Err(err) => {
+            error!("Failed to serialize audit record {:?}: {}", record, err);
+            return;
+        },
+    };
+    line.push(b'\n');
+    let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Err(err) = file.append(&line) {
+        error!("Failed to write audit record {:?} to {}: {}", record, file.path.display(), err);
+    }
+}
+
+impl AuditLog {
+    pub fn disabled(admin_role: String) -> Self {
+        Self { file: None, records: None, writer: None, admin_role }
+    }
+
+    pub fn open(path: &str, max_bytes: u64, keep: usize, admin_role: String) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open(&path)?;
+        let size = file.metadata()?.len();
+        let file = Arc::new(Mutex::new(AuditFile { path, file, size, max_bytes, keep }));
+        let (records, received) = mpsc::channel();
+        let written = file.clone();
+        let writer = thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || received.into_iter().for_each(|record| write(&written, record)))?;
+        Ok(Self { file: Some(file), records: Some(records), writer: Some(writer), admin_role })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        let Some(records) = &self.records else {
+            return;
+        };
+        if let Err(mpsc::SendError(record)) = records.send(record) {
+            error!("Audit writer stopped, dropping audit record {:?}", record);
+        }
+    }
+
+    /// Records `opened` now, and the matching `closed` event with the duration once the returned guard is dropped.
+    pub fn track(audit: &Arc<AuditLog>, opened: AuditRecord, closed: AuditEvent) -> Tracked {
+        let mut closed_record = opened.clone();
+        closed_record.event = closed;
+        audit.record(opened);
+        Tracked { audit: audit.clone(), closed: Some(closed_record), started: Instant::now() }
+    }
+
+    /// Whether `user` may query the log, anyone may when there's no login at all.
+    pub fn can_query(&self, user: Option<&CurrentUser>, login_enabled: bool) -> bool {
+        match user {
+            Some(user) => user.roles().contains(&self.admin_role),
+            None => !login_enabled,
+        }
+    }
+
+    /// The latest records matching `query`, oldest first.
+    pub fn query(&self, query: &AuditQuery) -> io::Result<Vec<AuditRecord>> {
+        let Some(file) = &self.file else {
+            return Ok(vec![]);
+        };
+        let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+        let files = {
+            // Opened under the lock so they aren't rotated between listing and opening them, read after it so recording goes on
+            let file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            file.files().into_iter()
+                .filter(|path| path.exists())
+                .map(|path| File::open(&path).map(|opened| (path, opened)))
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        let mut records = VecDeque::new();
+        for (path, opened) in files {
+            for line in BufReader::new(opened).lines() {
+                let record: AuditRecord = match serde_json::from_str(&line?) {
+                    Ok(record) => record,
+                    Err(err) => {
+                        warn!("Skipping unreadable audit record in {}: {}", path.display(), err);
+                        continue;
+                    },
+                };
+                if query.matches(&record) {
+                    records.push_back(record);
+                    if records.len() > limit {
+                        records.pop_front();
+                    }
+                }
+            }
+        }
+        Ok(records.into())
+    }
+}
+
+impl Drop for AuditLog {
+    /// Waits for the writer to write what was recorded so far.
+    fn drop(&mut self) {
+        self.records.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("Audit writer panicked");
+            }
+        }
+    }
+}
+
+/// Something being watched or connected, recorded as closed with its duration when dropped.
+pub struct Tracked {
+    audit: Arc<AuditLog>,
+    closed: Option<AuditRecord>,
+    started: Instant
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(mut closed) = self.closed.take() {
+            closed.timestamp = chrono::Utc::now().naive_utc();
+            closed.duration_ms = Some(self.started.elapsed().as_millis() as u64);
+            self.audit.record(closed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::{application, user};
+
+    fn record(event: AuditEvent, user: &str, application_name: &str, timestamp: &str) -> AuditRecord {
+        let mut record = AuditRecord::new(event, Some(user.to_string()), Some("127.0.0.1:50000".to_string()))
+            .with_application(application(application_name));
+        record.timestamp = timestamp.parse().unwrap();
+        record
+    }
+
+    #[test]
+    fn matches_everything_without_filters() {
+        assert!(AuditQuery::default().matches(&record(AuditEvent::StreamOpened, "alice", "payments-ledger", "2024-01-31T10:00:00")));
+        assert!(AuditQuery::default().matches(&AuditRecord::new(AuditEvent::LoginFailed, None, None)));
+    }
+
+    #[test]
+    fn filters_records() {
+        let record = record(AuditEvent::StreamOpened, "alice", "payments-ledger", "2024-01-31T10:00:00");
+        let query = |query: &str| actix_web::web::Query::<AuditQuery>::from_query(query).unwrap().into_inner();
+        assert!(query("user=alice&application=payments-*&event=StreamOpened").matches(&record));
+        assert!(query("from=2024-01-31T09:00:00&to=2024-01-31T10:00:00").matches(&record));
+        assert!(!query("user=bob").matches(&record));
+        assert!(!query("application=auth").matches(&record));
+        assert!(!query("event=StreamClosed").matches(&record));
+        assert!(!query("from=2024-01-31T10:00:01").matches(&record));
+        assert!(!query("application=*").matches(&AuditRecord::new(AuditEvent::Login, Some("alice".to_string()), None)));
+    }
+
+    #[test]
+    fn only_admins_query() {
+        let audit = AuditLog::disabled("admin".to_string());
+        assert!(audit.can_query(Some(&user("alice", &["developers", "admin"])), true));
+        assert!(!audit.can_query(Some(&user("bob", &["developers"])), true));
+        assert!(!audit.can_query(None, true));
+        assert!(audit.can_query(None, false));
+    }
+
+    #[test]
+    fn queries_rotated_files() {
+        let dir = std::env::temp_dir().join(format!("webtail-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log").to_string_lossy().to_string();
+        let audit = AuditLog::open(&path, 200, 5, "admin".to_string()).unwrap();
+        for (user, timestamp) in [("alice", "2024-01-31T10:00:00"), ("bob", "2024-01-31T11:00:00"), ("alice", "2024-01-31T12:00:00")] {
+            audit.record(record(AuditEvent::StreamOpened, user, "payments-ledger", timestamp));
+        }
+        // Dropping waits for the writer
+        drop(audit);
+
+        let audit = AuditLog::open(&path, 200, 5, "admin".to_string()).unwrap();
+        assert!(Path::new(&format!("{}.1", path)).exists());
+        let query = |query: &str| actix_web::web::Query::<AuditQuery>::from_query(query).unwrap().into_inner();
+        let users = |records: Vec<AuditRecord>| records.into_iter().map(|record| record.user.unwrap()).collect::<Vec<_>>();
+        assert_eq!(users(audit.query(&query("")).unwrap()), ["alice", "bob", "alice"]);
+        assert_eq!(users(audit.query(&query("user=alice&limit=1")).unwrap()), ["alice"]);
+        assert_eq!(audit.query(&query("user=alice&limit=1")).unwrap()[0].timestamp.to_string(), "2024-01-31 12:00:00");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
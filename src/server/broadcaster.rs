@@ -1,11 +1,288 @@
-use std::collections::BTreeMap;
+use std::{collections::{BTreeMap, HashMap}, sync::{atomic::{AtomicU64, Ordering}, Arc}};
 
-use tokio::sync::{broadcast::Sender, Mutex};
+use actix_ws::Session;
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio::sync::{broadcast::{self, Receiver, Sender}, Mutex, MutexGuard};
 
 use crate::{message::Message, Applicatiton};
 
-pub type Broadcasters = Mutex<BTreeMap<Applicatiton, Sender<Message>>>;
+use super::history::{History, Replay};
 
-pub fn new_broadcasters() -> Broadcasters {
-    Mutex::new(BTreeMap::new())
-}
\ No newline at end of file
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How long what a client delivered is remembered after it disconnected, to drop the frames it resends when it reconnects.
+const DELIVERED_TTL: TimeDelta = TimeDelta::hours(1);
+/// How long the history of an application is kept after its last publisher left, for new subscribers once a client comes back.
+const IDLE_HISTORY_TTL: TimeDelta = TimeDelta::hours(1);
+
+#[derive(Debug, Clone)]
+pub struct BroadcasterConfiguration {
+    history_lines: usize,
+    history_bytes: usize,
+    /// Messages a subscriber may fall behind by before it skips some
+    capacity: usize
+}
+
+impl BroadcasterConfiguration {
+    pub fn new(history_lines: usize, history_bytes: usize, capacity: usize) -> Self {
+        Self { history_lines, history_bytes, capacity: capacity.max(1) }
+    }
+}
+
+/// Registry of the applications currently published by clients.
+pub struct Broadcasters {
+    configuration: BroadcasterConfiguration,
+    applications: Mutex<BTreeMap<Applicatiton, Broadcaster>>,
+    /// What each client with an id delivered, kept across its reconnects
+    delivered: std::sync::Mutex<HashMap<String, ClientDeliveries>>,
+    /// Histories of applications without publishers
+    idle_histories: std::sync::Mutex<BTreeMap<Applicatiton, IdleHistory>>
+}
+
+/// Last sequence number delivered by a client, shared by its connections.
+#[derive(Debug, Clone, Default)]
+pub struct Delivered(Arc<AtomicU64>);
+
+impl Delivered {
+    /// Whether a frame is new rather than resent by the client after a reconnect, recording it as delivered if so.
+    pub fn first_delivery(&self, sequence: u64) -> bool {
+        self.0.fetch_max(sequence, Ordering::Relaxed) < sequence
+    }
+}
+
+struct ClientDeliveries {
+    delivered: Delivered,
+    connections: usize,
+    /// When to forget the client, once its last connection closed
+    expires_at: Option<DateTime<Utc>>
+}
+
+struct IdleHistory {
+    history: Arc<Mutex<History>>,
+    expires_at: DateTime<Utc>
+}
+
+impl Broadcasters {
+    pub async fn lock(&self) -> MutexGuard<'_, BTreeMap<Applicatiton, Broadcaster>> {
+        self.applications.lock().await
+    }
+
+    /// Broadcaster of an application getting its first publisher, carrying on with its history if it had one recently.
+    fn broadcaster(&self, application: &Applicatiton) -> Broadcaster {
+        let mut idle_histories = self.idle_histories.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Utc::now();
+        idle_histories.retain(|_, idle| idle.expires_at > now);
+        let history = idle_histories.remove(application).map(|idle| idle.history)
+            .unwrap_or_else(|| Arc::new(Mutex::new(History::new(self.configuration.history_lines, self.configuration.history_bytes))));
+        let (tx, _) = broadcast::channel(self.configuration.capacity);
+        Broadcaster { publisher: Publisher { tx, history }, publishers: BTreeMap::new() }
+    }
+
+    /// Keeps the history of an application whose last publisher left for a while.
+    fn idle(&self, application: &Applicatiton, broadcaster: &Broadcaster) {
+        let mut idle_histories = self.idle_histories.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let idle = IdleHistory { history: broadcaster.publisher.history.clone(), expires_at: Utc::now() + IDLE_HISTORY_TTL };
+        idle_histories.insert(application.clone(), idle);
+    }
+
+    /// Publishes an application without a client connection, for the route tests.
+    #[cfg(test)]
+    pub async fn publish(&self, application: &Applicatiton) -> Publisher {
+        let mut locked_broadcasters = self.lock().await;
+        locked_broadcasters.entry(application.clone()).or_insert_with(|| self.broadcaster(application)).publisher()
+    }
+
+    /// What a connecting client delivered before, nothing for clients without an id as they can't be recognized.
+    pub fn connected(&self, client_id: Option<&str>) -> Delivered {
+        let Some(client_id) = client_id else {
+            return Delivered::default();
+        };
+        let mut delivered = self.delivered.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Utc::now();
+        delivered.retain(|_, client| client.expires_at.is_none_or(|expires_at| expires_at > now));
+        let client = delivered.entry(client_id.to_string())
+            .or_insert_with(|| ClientDeliveries { delivered: Delivered::default(), connections: 0, expires_at: None });
+        client.connections += 1;
+        client.expires_at = None;
+        client.delivered.clone()
+    }
+
+    /// Starts forgetting what the client delivered once its last connection is gone.
+    pub fn disconnected(&self, client_id: Option<&str>) {
+        let Some(client_id) = client_id else {
+            return;
+        };
+        let mut delivered = self.delivered.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(client) = delivered.get_mut(client_id) {
+            client.connections = client.connections.saturating_sub(1);
+            if client.connections == 0 {
+                client.expires_at = Some(Utc::now() + DELIVERED_TTL);
+            }
+        }
+    }
+}
+
+/// Message tagged with its per-application sequence number, used as the SSE event id.
+#[derive(Debug, Clone)]
+pub struct SequencedMessage {
+    pub sequence: u64,
+    pub message: Message
+}
+
+/// Handle used by a client connection to publish messages of an application.
+/// Publishing and subscribing share the history lock, so subscribers never miss or repeat a message.
+#[derive(Clone)]
+pub struct Publisher {
+    tx: Sender<SequencedMessage>,
+    history: Arc<Mutex<History>>
+}
+
+impl Publisher {
+    /// Publishes to the current subscribers and returns how many there are, the history keeps the message for later ones either way.
+    pub async fn send(&self, message: Message) -> usize {
+        let mut history = self.history.lock().await;
+        let message = history.push(message);
+        self.tx.send(message).unwrap_or(0)
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.tx.receiver_count()
+    }
+}
+
+/// Broadcast channel of an application, shared by every client publishing under that application.
+pub struct Broadcaster {
+    publisher: Publisher,
+    publishers: BTreeMap<u64, Session>
+}
+
+impl Broadcaster {
+    pub fn publisher(&self) -> Publisher {
+        self.publisher.clone()
+    }
+
+    /// Sessions of the clients publishing the application, for sending them requests.
+    pub fn sessions(&self) -> Vec<Session> {
+        self.publishers.values().cloned().collect()
+    }
+
+    /// Returns the buffered history, or only what was published after `last_event_id` when resuming,
+    /// together with a receiver for everything published after it.
+    pub async fn subscribe(&self, last_event_id: Option<u64>) -> (Replay, Receiver<SequencedMessage>) {
+        let history = self.publisher.history.lock().await;
+        let replay = match last_event_id {
+            Some(last_event_id) => history.since(last_event_id),
+            None => Replay { gap: None, messages: history.snapshot() },
+        };
+        (replay, self.publisher.tx.subscribe())
+    }
+}
+
+pub fn new_broadcasters(configuration: BroadcasterConfiguration) -> Broadcasters {
+    Broadcasters { configuration, applications: Mutex::new(BTreeMap::new()), delivered: std::sync::Mutex::new(HashMap::new()), idle_histories: std::sync::Mutex::new(BTreeMap::new()) }
+}
+
+/// Unique id of an inbound client connection, used to tell publishers of the same application apart.
+pub fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Registers a publisher for the application and returns the handle shared by all of its publishers.
+pub async fn register(broadcasters: &Broadcasters, application: &Applicatiton, connection_id: u64, session: Session) -> Publisher {
+    let mut locked_broadcasters = broadcasters.lock().await;
+    let broadcaster = locked_broadcasters.entry(application.clone())
+        .or_insert_with(|| broadcasters.broadcaster(application));
+    broadcaster.publishers.insert(connection_id, session);
+    broadcaster.publisher()
+}
+
+/// Removes a publisher from the application. The application is only dropped when its last publisher is gone,
+/// in which case its handle is returned so the subscribers can be notified. Its history is kept for a while longer.
+pub async fn unregister(broadcasters: &Broadcasters, application: &Applicatiton, connection_id: u64) -> Option<Publisher> {
+    let mut locked_broadcasters = broadcasters.lock().await;
+    let broadcaster = locked_broadcasters.get_mut(application)?;
+    broadcaster.publishers.remove(&connection_id);
+    if !broadcaster.publishers.is_empty() {
+        return None;
+    }
+    let broadcaster = locked_broadcasters.remove(application)?;
+    broadcasters.idle(application, &broadcaster);
+    Some(broadcaster.publisher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message::DataMessage, server::testing::application};
+
+    fn broadcasters() -> Broadcasters {
+        new_broadcasters(BroadcasterConfiguration::new(10, 1024, 10))
+    }
+
+    #[test]
+    fn drops_frames_resent_after_a_reconnect() {
+        let broadcasters = broadcasters();
+        let delivered = broadcasters.connected(Some("client"));
+        assert!(delivered.first_delivery(1));
+        assert!(delivered.first_delivery(2));
+        broadcasters.disconnected(Some("client"));
+
+        let delivered = broadcasters.connected(Some("client"));
+        assert!(!delivered.first_delivery(2));
+        assert!(delivered.first_delivery(3));
+    }
+
+    #[test]
+    fn forgets_clients_once_disconnected_long_enough() {
+        let broadcasters = broadcasters();
+        broadcasters.connected(None).first_delivery(1);
+        broadcasters.connected(Some("gone")).first_delivery(1);
+        broadcasters.connected(Some("connected")).first_delivery(1);
+        broadcasters.disconnected(Some("gone"));
+        let mut delivered = broadcasters.delivered.lock().unwrap();
+        assert_eq!(delivered.len(), 2);
+        delivered.get_mut("gone").unwrap().expires_at = Some(Utc::now());
+        drop(delivered);
+
+        broadcasters.connected(Some("new"));
+        let delivered = broadcasters.delivered.lock().unwrap();
+        assert!(!delivered.contains_key("gone"));
+        assert!(delivered.contains_key("connected"));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_history_while_the_application_has_no_publishers() {
+        let broadcasters = broadcasters();
+        let broadcaster = broadcasters.broadcaster(&application("payments"));
+        broadcaster.publisher().send(Message::Data(DataMessage::new("before".to_string(), application("payments"), false))).await;
+        broadcasters.idle(&application("payments"), &broadcaster);
+        broadcasters.idle(&application("auth"), &broadcasters.broadcaster(&application("auth")));
+        broadcasters.idle_histories.lock().unwrap().get_mut(&application("auth")).unwrap().expires_at = Utc::now();
+
+        let (replay, _rx) = broadcasters.broadcaster(&application("payments")).subscribe(None).await;
+        assert_eq!(replay.messages.iter().map(|message| message.message.data().unwrap().row()).collect::<Vec<_>>(), ["before"]);
+        assert!(!broadcasters.idle_histories.lock().unwrap().contains_key(&application("auth")));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn subscribers_joining_while_publishing_miss_and_repeat_nothing() {
+        let broadcaster = new_broadcasters(BroadcasterConfiguration::new(1000, 1 << 20, 1000)).broadcaster(&application("payments"));
+        let publisher = broadcaster.publisher();
+        let published = tokio::spawn(async move {
+            for row in 0..500 {
+                publisher.send(Message::Data(DataMessage::new(row.to_string(), application("payments"), false))).await;
+                tokio::task::yield_now().await;
+            }
+        });
+        for _ in 0..5 {
+            tokio::task::yield_now().await;
+            let (replay, mut rx) = broadcaster.subscribe(None).await;
+            let mut rows: Vec<String> = replay.messages.iter().map(|message| message.message.data().unwrap().row().to_string()).collect();
+            while rows.len() < 500 {
+                rows.push(rx.recv().await.unwrap().message.data().unwrap().row().to_string());
+            }
+            assert_eq!(rows, (0..500).map(|row| row.to_string()).collect::<Vec<_>>());
+        }
+        published.await.unwrap();
+    }
+}
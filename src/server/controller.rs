@@ -1,2 +1,34 @@
+use actix_web::{middleware, web};
+
 pub mod outbound;
-pub mod inbound;
\ No newline at end of file
+pub mod inbound;
+pub mod login;
+
+/// Registers every route of the server. The login routes come before the `/api` scope, every route in it needs a login session when there are users.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        // Login routes
+        .service(login::login)
+        .service(login::logout)
+        .service(login::current_session)
+        .service(login::oidc_login)
+        .service(login::oidc_callback)
+        // WebSocket route
+        .service(inbound::data_inbound_ws)
+        .service(
+            web::scope("/api")
+                .wrap(middleware::from_fn(login::require_login))
+                // API routes
+                .service(outbound::hello)
+                // SSE route
+                .service(outbound::data_outbound_sse)
+                // API route to get the current registered applications
+                .service(outbound::current_registered_applications)
+                // API route to ask the clients of an application for an older log file
+                .service(outbound::request_history)
+                // API route for the counters of what clients sent
+                .service(outbound::current_metrics)
+                // API route for admins to query the audit log
+                .service(outbound::query_audit_log)
+        );
+}
@@ -1,20 +1,16 @@
 use std::{sync::Arc, time::Duration};
 
-use actix_web::{rt, web, Error, HttpRequest, HttpResponse, Result};
-use actix_ws::{AggregatedMessage, ProtocolError, Session};
+use actix_web::{http::header::{self, HeaderName, HeaderValue}, rt, web, Error, HttpRequest, HttpResponse, Result};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, ProtocolError, Session};
 use log::{debug, error, info, trace, warn};
-use tokio::{sync::broadcast::{self, Sender}, time::sleep};
+use tokio::time::sleep;
 
-use crate::{message::{BinaryMessage, Message, SystemMessage, SystemMessages}, server::broadcaster::Broadcasters, Applicatiton};
+use crate::{message::{compression, handshake::{self, Handshake, PausePolicy}, BinaryMessage, Message, SystemMessage, SystemMessages}, server::{audit::{AuditEvent, AuditLog, AuditRecord}, broadcaster::{self, Broadcasters, Delivered, Publisher}, metrics::Metrics, tokens::{Rejection, TokenStore}}, Applicatiton};
 
 #[actix_web::get("/ws")]
-pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcasters: web::Data<Arc<Broadcasters>>) -> Result<HttpResponse, Error> {
-    match req.peer_addr() {
-        Some(addr) => info!("WebSocket connection request from {}", addr),
-        None => warn!("WebSocket connection request from unknown source"),
-    }
-    
-    let (res, mut session, stream) = actix_ws::handle(&req, stream)?;
+pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcasters: web::Data<Arc<Broadcasters>>, metrics: web::Data<Arc<Metrics>>, tokens: web::Data<Arc<TokenStore>>, audit: web::Data<Arc<AuditLog>>) -> Result<HttpResponse, Error> {
+    let peer = req.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|| "unknown source".to_string());
+    info!("WebSocket connection request from {}", peer);
 
     let application = match req.headers().get("Application") {
         Some(app) => match app.to_str() {
@@ -30,14 +26,44 @@ pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcaster
         }
     };
     
-    let application: Applicatiton = match serde_json::from_str(&application) {
+    let application: Applicatiton = match serde_json::from_str(application) {
         Ok(app) => app,
         Err(err) => {
             error!("Failed to parse application JSON: {} with error: {}", application, err);
             return Ok(HttpResponse::UnprocessableEntity().finish());
         },
     };
-    
+
+    let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+    if let Err(rejection) = tokens.authorize(authorization, &application) {
+        warn!("Rejecting client from {} publishing application {}: {}", peer, application.name(), rejection);
+        return Ok(match rejection {
+            Rejection::NotAllowed => HttpResponse::Forbidden().finish(),
+            _ => HttpResponse::Unauthorized().insert_header((header::WWW_AUTHENTICATE, "Bearer")).finish(),
+        });
+    }
+
+    let (mut res, mut session, stream) = actix_ws::handle(&req, stream)?;
+
+    let header = |name| req.headers().get(name).and_then(|value| value.to_str().ok());
+    let handshake = Handshake::from_headers(header(handshake::PROTOCOL_VERSION_HEADER), header(handshake::FEATURES_HEADER))
+        .and_then(|client| Handshake::default().negotiate(&client));
+    let handshake = match handshake {
+        Ok(handshake) => handshake,
+        Err(reason) => {
+            error!("Rejecting client of application {}: {}", application.name(), reason);
+            // The upgrade goes through so the client gets to read why it's closed
+            let _ = session.close(Some(CloseReason { code: CloseCode::Protocol, description: Some(reason) })).await;
+            return Ok(res);
+        }
+    };
+    info!("Agreed on {} for application: {}", handshake, application.name());
+    for (name, value) in [(handshake::PROTOCOL_VERSION_HEADER, handshake.version_header()), (handshake::FEATURES_HEADER, handshake.features_header())] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            res.headers_mut().insert(HeaderName::from_static(name), value);
+        }
+    }
+
     let mut stream = stream
     .aggregate_continuations()
     // aggregate continuation frames up to 1MiB
@@ -45,11 +71,17 @@ pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcaster
     
     info!("WebSocket connection established for application: {}", application.name());
 
-    let (tx, _) = broadcast::channel(100);
-
-    let mut locked_broadcasters = broadcasters.lock().await;
-    locked_broadcasters.insert(application.clone(), tx.clone());
-    drop(locked_broadcasters);
+    let connection_id = broadcaster::next_connection_id();
+    let tx = broadcaster::register(&broadcasters, &application, connection_id, session.clone()).await;
+    // Without an id resent frames can only be told apart within the connection
+    let announced_client_id = header(handshake::CLIENT_ID_HEADER).map(str::to_string);
+    let delivered = broadcasters.connected(announced_client_id.as_deref());
+    let client_id = announced_client_id.clone().unwrap_or_else(|| format!("connection-{}", connection_id));
+    let pause_policy = PausePolicy::from_header(header(handshake::PAUSE_POLICY_HEADER));
+    info!("Client of application {} {:?}s lines while paused", application.name(), pause_policy);
+    let connection = Connection { application: application.clone(), client_id, delivered, pause_policy };
+    let registered = AuditRecord::new(AuditEvent::ClientRegistered, tokens.fingerprint(authorization), Some(peer)).with_application(application.clone());
+    let tracked = AuditLog::track(&audit, registered, AuditEvent::ClientDisconnected);
 
     let start_message = Message::System(SystemMessage::new(application.clone(), SystemMessages::Start));
     let start_message = match serde_json::to_string(&start_message) {
@@ -71,10 +103,12 @@ pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcaster
     let app = application.clone();
 
     let mut ping_session = session.clone();
+    let ping_metrics = Arc::clone(&metrics);
     let handle = rt::spawn(async move {
-        while let Some(msg) = stream.recv().await {
-            if tx.receiver_count() > 0 {
-                match handle_message(msg, &mut session, &tx).await {
+        'RECEIVE: while let Some(msg) = stream.recv().await {
+            // Clients forwarding while paused are never paused, what they send keeps the history warm
+            if tx.receiver_count() > 0 || connection.pause_policy == PausePolicy::Forward {
+                match handle_message(msg, &mut session, Some(&tx), &connection, &metrics).await {
                     false => break,
                     _ => {
                         continue;
@@ -82,6 +116,12 @@ pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcaster
                 }
             }
 
+            // What is already on its way is only thrown away for clients that drop lines while paused anyway
+            let keep = (connection.pause_policy != PausePolicy::Drop).then_some(&tx);
+            if !handle_message(msg, &mut session, keep, &connection, &metrics).await {
+                break;
+            }
+
             let pause_message = Message::System(SystemMessage::new(application.clone(), SystemMessages::Pause));
             let pause_message = match serde_json::to_string(&pause_message) {
                 Ok(msg) => msg,
@@ -101,12 +141,13 @@ pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcaster
             loop {
                 if tx.receiver_count() > 0 {
                     // Consume any pending messages in the stream buffer
-                    while let Ok(msg) = tokio::time::timeout(
+                    while let Ok(Some(msg)) = tokio::time::timeout(
                         Duration::from_millis(50), 
                         stream.recv()
                     ).await {
-                        debug!("Consuming pending message: {:?}", msg);
-                        continue;
+                        if !handle_message(msg, &mut session, keep, &connection, &metrics).await {
+                            break 'RECEIVE;
+                        }
                     }
 
                     let resume_message = Message::System(SystemMessage::new(application.clone(), SystemMessages::Resume));
@@ -129,6 +170,8 @@ pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcaster
                 sleep(Duration::from_secs(1)).await;
             }
         }
+        // Aborting the task drops it as well, either way the client is recorded as disconnected
+        drop(tracked);
         info!("webSocket connection closed");
     });
 
@@ -140,57 +183,98 @@ pub async fn data_inbound_ws(req: HttpRequest, stream: web::Payload, broadcaster
         }
         
         info!("Ping failed, aborting message handler");
-        let mut locked_broadcasters = broadcasters.lock().await;
-        let rx = locked_broadcasters.remove(&app);
-        drop(locked_broadcasters);
-        match rx {
+        // Stopped first, so nothing it still receives counts towards metrics that are removed below
+        handle.abort();
+        broadcasters.disconnected(announced_client_id.as_deref());
+        match broadcaster::unregister(&broadcasters, &app, connection_id).await {
             Some(rx) => {
-                let _ = rx.send(Message::ClientDisconnect);
+                rx.send(Message::ClientDisconnect).await;
+                ping_metrics.remove(&app);
             },
             None => {
-                error!("No broadcaster found for application: {}", app.name());
+                info!("Other publishers still connected for application: {}", app.name());
             }
         }
-        handle.abort();
         info!("WebSocket connection terminated by ping monitor");
     });
     
     Ok(res)
 }
 
-async fn handle_message(msg: Result<AggregatedMessage, ProtocolError>, session: &mut Session, tx: &Sender<Message>) -> bool {
+/// The client side of an inbound connection.
+struct Connection {
+    application: Applicatiton,
+    client_id: String,
+    delivered: Delivered,
+    pause_policy: PausePolicy
+}
+
+/// Publishes what the client sent, or drops it without a publisher. Frames are acknowledged and recorded as delivered
+/// either way, a client resending dropped lines would only get them out of order later.
+async fn handle_message(msg: Result<AggregatedMessage, ProtocolError>, session: &mut Session, tx: Option<&Publisher>, connection: &Connection, metrics: &Metrics) -> bool {
+    let application = &connection.application;
     match msg {
         Ok(AggregatedMessage::Text(text)) => {
             // echo text message
             let message: Result<Message, serde_json::Error> = serde_json::from_str(&text);
             match message {
-                Ok(message) => {
-                    debug!("Received message: {:#?}", message);
-                    match tx.send(message) {
-                        Ok(n) => trace!("message broadcasted to {} subscribers", n),
-                        Err(err) => error!("error broadcasting message: {:?}", err),
-                    }
-                }
+                Ok(message) => match tx {
+                    Some(tx) => {
+                        debug!("Received message: {:#?}", message);
+                        let n = tx.send(message).await;
+                        trace!("message broadcasted to {} subscribers", n);
+                    },
+                    None => debug!("Dropping message while paused: {:?}", message),
+                },
                 Err(e) => {
                     error!("Failed to parse message: {:?}", e);
+                    close_unsupported(session).await;
+                    return false;
                 }
             }
         }
         
         Ok(AggregatedMessage::Binary(bin)) => {
             // process binary message
-            let binary_message: Result<BinaryMessage, std::io::Error> = borsh::from_slice(&bin);
-            match binary_message {
-                Ok(binary_message) => {
-                    let message = Message::from(binary_message);
-                    debug!("Received binary message: {:#?}", message);
-                    match tx.send(message) {
-                        Ok(n) => trace!("message broadcasted to {} subscribers", n),
-                        Err(err) => error!("error broadcasting message: {:?}", err),
+            let binary_message = borsh::from_slice::<BinaryMessage>(&bin).and_then(|binary_message| match binary_message {
+                BinaryMessage::Compressed(compressed) => {
+                    let decompressed = compression::decompress(&compressed)?;
+                    metrics.received(application, bin.len(), Some(decompressed.len()));
+                    borsh::from_slice(&decompressed)
+                },
+                binary_message => {
+                    metrics.received(application, bin.len(), None);
+                    Ok(binary_message)
+                },
+            });
+            // Frames to acknowledge carry their sequence number around the actual message
+            let (sequence, binary_message) = match binary_message {
+                Ok(BinaryMessage::Sequenced(sequence, bytes)) => (Some(sequence), borsh::from_slice::<BinaryMessage>(&bytes)),
+                binary_message => (None, binary_message),
+            };
+            match binary_message.and_then(|binary_message| binary_message.into_messages(application)) {
+                Ok(messages) => {
+                    let first_delivery = match sequence {
+                        Some(sequence) => connection.delivered.first_delivery(sequence),
+                        None => true,
+                    };
+                    match (first_delivery, tx) {
+                        (true, Some(tx)) => for message in messages {
+                            debug!("Received binary message: {:#?}", message);
+                            let n = tx.send(message).await;
+                            trace!("message broadcasted to {} subscribers", n);
+                        },
+                        (true, None) => debug!("Dropping {} messages while paused", messages.len()),
+                        (false, _) => debug!("Dropping frame {} resent by client {}", sequence.unwrap_or_default(), connection.client_id),
+                    }
+                    if let Some(sequence) = sequence {
+                        return acknowledge(session, application, sequence).await;
                     }
                 },
                 Err(e) => {
-                    error!("Failed to parse message: {:?}", e)
+                    error!("Failed to parse message: {:?}", e);
+                    close_unsupported(session).await;
+                    return false;
                 },
             }
         }
@@ -221,4 +305,161 @@ async fn handle_message(msg: Result<AggregatedMessage, ProtocolError>, session:
     }
 
     true
-}
\ No newline at end of file
+}
+
+/// A message that can't be parsed means the client speaks something else, retrying won't help so it's told why.
+async fn close_unsupported(session: &mut Session) {
+    let reason = CloseReason {
+        code: CloseCode::Unsupported,
+        description: Some(format!("unsupported message, this server speaks protocol versions {} to {}", handshake::MIN_PROTOCOL_VERSION, handshake::PROTOCOL_VERSION))
+    };
+    if let Err(err) = session.clone().close(Some(reason)).await {
+        error!("Failed to close session: {}", err);
+    }
+}
+
+/// Tells the client every frame up to and including `sequence` was handled, false if the session is gone.
+async fn acknowledge(session: &mut Session, application: &Applicatiton, sequence: u64) -> bool {
+    let ack_message = Message::System(SystemMessage::new(application.clone(), SystemMessages::Ack { sequence }));
+    let ack_message = match serde_json::to_string(&ack_message) {
+        Ok(msg) => msg,
+        Err(err) => {
+            error!("Failed to serialize ack message: {}", err);
+            return true;
+        }
+    };
+    match session.text(ack_message).await {
+        Ok(_) => true,
+        Err(err) => {
+            error!("Failed to send ack message: {}", err);
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use actix_web::{http::{header, StatusCode}, test, App, HttpServer};
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio_tungstenite::{tungstenite::{self, client::IntoClientRequest}, MaybeTlsStream, WebSocketStream};
+
+    use super::*;
+    use crate::{message::DataMessage, server::testing::{application, from_json, TestServer}};
+
+    type Client = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    fn connect(application: &str, token: Option<&str>) -> test::TestRequest {
+        let request = test::TestRequest::get().uri("/ws")
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .insert_header(("Application", json!({"SinglePod": application}).to_string()));
+        match token {
+            Some(token) => request.insert_header((header::AUTHORIZATION, format!("Bearer {}", token))),
+            None => request,
+        }
+    }
+
+    #[actix_web::test]
+    async fn clients_need_a_token_for_the_application() {
+        let tokens = json!({"shared": ["*"], "billing": ["billing-*", "invoices"]});
+        let server = TestServer { tokens: from_json(tokens), ..TestServer::default() };
+        let app = test::init_service(App::new().configure(server.configure())).await;
+
+        for (application, token, status) in [
+            ("auth", None, StatusCode::UNAUTHORIZED),
+            ("auth", Some("guessed"), StatusCode::UNAUTHORIZED),
+            ("auth", Some("billing"), StatusCode::FORBIDDEN),
+            ("billing-api", Some("billing"), StatusCode::SWITCHING_PROTOCOLS),
+            ("auth", Some("shared"), StatusCode::SWITCHING_PROTOCOLS),
+        ] {
+            let res = test::call_service(&app, connect(application, token).to_request()).await;
+            assert_eq!(res.status(), status, "{} with {:?}", application, token);
+            if status == StatusCode::UNAUTHORIZED {
+                assert_eq!(res.headers().get(header::WWW_AUTHENTICATE).unwrap(), "Bearer");
+            }
+        }
+    }
+
+    /// Connects a client acknowledging frames and doing `pause_policy` with lines while paused to a running server.
+    async fn connect_client(server: &TestServer, pause_policy: PausePolicy) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut request = format!("ws://{}/ws", listener.local_addr().unwrap()).into_client_request().unwrap();
+        let served = server.clone();
+        actix_web::rt::spawn(HttpServer::new(move || App::new().configure(served.configure())).workers(1).listen(listener).unwrap().run());
+        for (name, value) in [
+            ("Application", json!({"SinglePod": "auth"}).to_string()),
+            (handshake::PROTOCOL_VERSION_HEADER, handshake::PROTOCOL_VERSION.to_string()),
+            (handshake::FEATURES_HEADER, "ack".to_string()),
+            (handshake::CLIENT_ID_HEADER, "client".to_string()),
+            (handshake::PAUSE_POLICY_HEADER, pause_policy.header().to_string()),
+        ] {
+            request.headers_mut().insert(name, value.parse().unwrap());
+        }
+        tokio_tungstenite::connect_async(request).await.unwrap().0
+    }
+
+    async fn send_line(client: &mut Client, sequence: u64, row: &str) {
+        let data = Message::Data(DataMessage::new(row.to_string(), application("auth"), false));
+        let frame = BinaryMessage::Sequenced(sequence, borsh::to_vec(&BinaryMessage::from(data)).unwrap());
+        client.send(tungstenite::Message::binary(borsh::to_vec(&frame).unwrap())).await.unwrap();
+    }
+
+    async fn receive(client: &mut Client) -> SystemMessages {
+        loop {
+            if let tungstenite::Message::Text(text) = client.next().await.unwrap().unwrap() {
+                return serde_json::from_str::<Message>(&text).unwrap().system().unwrap().message().clone();
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn acknowledges_lines_dropped_while_paused() {
+        let server = TestServer::default();
+        let mut client = connect_client(&server, PausePolicy::Drop).await;
+        assert_eq!(receive(&mut client).await, SystemMessages::Start);
+
+        send_line(&mut client, 1, "no one is watching").await;
+        assert_eq!(receive(&mut client).await, SystemMessages::Ack { sequence: 1 });
+        assert_eq!(receive(&mut client).await, SystemMessages::Pause);
+
+        send_line(&mut client, 2, "sent before the pause arrived").await;
+        let (replay, mut rx) = server.broadcasters.lock().await.get(&application("auth")).unwrap().subscribe(None).await;
+        assert_eq!(receive(&mut client).await, SystemMessages::Ack { sequence: 2 });
+        assert_eq!(receive(&mut client).await, SystemMessages::Resume);
+
+        assert!(replay.messages.is_empty());
+        assert!(rx.try_recv().is_err());
+        // Resent after a reconnect they are dropped again
+        assert!(!server.broadcasters.connected(Some("client")).first_delivery(2));
+    }
+
+    #[actix_web::test]
+    async fn keeps_the_history_warm_for_forwarding_clients() {
+        let server = TestServer::default();
+        let mut client = connect_client(&server, PausePolicy::Forward).await;
+        assert_eq!(receive(&mut client).await, SystemMessages::Start);
+
+        send_line(&mut client, 1, "no one is watching").await;
+        send_line(&mut client, 2, "still no one").await;
+        // Never paused, the acks are all it gets
+        assert_eq!(receive(&mut client).await, SystemMessages::Ack { sequence: 1 });
+        assert_eq!(receive(&mut client).await, SystemMessages::Ack { sequence: 2 });
+
+        let (replay, _rx) = server.broadcasters.lock().await.get(&application("auth")).unwrap().subscribe(None).await;
+        let rows: Vec<&str> = replay.messages.iter().filter_map(|sequenced| sequenced.message.data()).map(DataMessage::row).collect();
+        assert_eq!(rows, ["no one is watching", "still no one"]);
+    }
+
+    #[actix_web::test]
+    async fn any_client_publishes_without_tokens() {
+        let server = TestServer::default();
+        let app = test::init_service(App::new().configure(server.configure())).await;
+        let res = test::call_service(&app, connect("auth", None).to_request()).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+}
@@ -0,0 +1,238 @@
+use std::sync::Arc;
+
+use actix_web::{body::{EitherBody, MessageBody}, cookie::{time, Cookie}, dev::{ServiceRequest, ServiceResponse}, get, http::header, middleware::Next, post, web, Error, HttpMessage, HttpRequest, HttpResponse, Responder};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::server::{audit::{AuditEvent, AuditLog, AuditRecord}, oidc::{self, Oidc}, sessions::{CurrentUser, Sessions, SESSION_COOKIE}, users::Users};
+
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    /// Who is logged in, none when no one is or when login is disabled
+    user: Option<String>,
+    login_required: bool,
+    /// Whether users log in with the OIDC provider at `/api/oidc/login`
+    sso: bool
+}
+
+#[derive(Deserialize)]
+struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>
+}
+
+/// Cookie keeping the state of an OIDC login in the browser that started it, only sent back to the callback.
+fn state_cookie(sessions: &Sessions, state: String) -> Cookie<'static> {
+    sessions.cookie_builder(oidc::STATE_COOKIE, state)
+        .path("/api/oidc")
+        .max_age(time::Duration::minutes(oidc::PENDING_LOGIN_TTL_MINUTES))
+        .finish()
+}
+
+fn state_removal_cookie(sessions: &Sessions) -> Cookie<'static> {
+    let mut cookie = state_cookie(sessions, String::new());
+    cookie.make_removal();
+    cookie
+}
+
+fn peer(req: &HttpRequest) -> String {
+    req.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|| "unknown source".to_string())
+}
+
+/// Middleware of the `/api` scope turning away requests made without a login session, when there are users or an OIDC provider to log in with.
+pub async fn require_login(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let login_required = req.app_data::<web::Data<Arc<Users>>>().map(|users| users.is_enabled()).unwrap_or(false)
+        || req.app_data::<web::Data<Arc<Oidc>>>().map(|oidc| oidc.is_enabled()).unwrap_or(false);
+    if login_required {
+        let user = match (req.cookie(SESSION_COOKIE), req.app_data::<web::Data<Arc<Sessions>>>()) {
+            (Some(cookie), Some(sessions)) => sessions.user(cookie.value()),
+            _ => None,
+        };
+        match user {
+            Some(user) => {
+                req.extensions_mut().insert(user);
+            },
+            None => {
+                info!("Rejecting request to {} without a login session", req.path());
+                return Ok(req.into_response(HttpResponse::Unauthorized().finish()).map_into_right_body());
+            },
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[post("/api/login")]
+pub async fn login(req: HttpRequest, users: web::Data<Arc<Users>>, sessions: web::Data<Arc<Sessions>>, audit: web::Data<Arc<AuditLog>>, credentials: web::Json<Credentials>) -> impl Responder {
+    let peer = peer(&req);
+    if !users.is_enabled() {
+        warn!("Login attempt from {} while login is disabled", peer);
+        return HttpResponse::NotFound().finish();
+    }
+
+    let Credentials { username, password } = credentials.into_inner();
+    let checked_users = Arc::clone(&users);
+    let name = username.clone();
+    // Argon2 is slow on purpose, it would hold up everything else on this worker
+    let verified = match web::block(move || checked_users.verify(&name, &password)).await {
+        Ok(verified) => verified,
+        Err(err) => {
+            error!("Failed to verify password of user {}: {}", username, err);
+            return HttpResponse::InternalServerError().finish();
+        },
+    };
+    if !verified {
+        warn!("Failed login of user {} from {}", username, peer);
+        audit.record(AuditRecord::new(AuditEvent::LoginFailed, Some(username), Some(peer)).with_detail("password".to_string()));
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    info!("User {} logged in from {}", username, peer);
+    audit.record(AuditRecord::new(AuditEvent::Login, Some(username.clone()), Some(peer)).with_detail("password".to_string()));
+    let id = sessions.create(CurrentUser::new(username.clone(), users.roles(&username)));
+    HttpResponse::Ok()
+        .cookie(sessions.cookie(id))
+        .json(SessionResponse { user: Some(username), login_required: true, sso: false })
+}
+
+#[post("/api/logout")]
+pub async fn logout(req: HttpRequest, sessions: web::Data<Arc<Sessions>>) -> impl Responder {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        if let Some(user) = sessions.user(cookie.value()) {
+            info!("User {} logged out", user.name());
+        }
+        sessions.remove(cookie.value());
+    }
+    HttpResponse::NoContent().cookie(sessions.removal_cookie()).finish()
+}
+
+/// Who is logged in, so the UI knows whether to show the login page.
+#[get("/api/session")]
+pub async fn current_session(req: HttpRequest, users: web::Data<Arc<Users>>, oidc: web::Data<Arc<Oidc>>, sessions: web::Data<Arc<Sessions>>) -> impl Responder {
+    let user = req.cookie(SESSION_COOKIE).and_then(|cookie| sessions.user(cookie.value()));
+    HttpResponse::Ok().json(SessionResponse {
+        user: user.as_ref().map(CurrentUser::name).map(str::to_string),
+        login_required: users.is_enabled() || oidc.is_enabled(),
+        sso: oidc.is_enabled(),
+    })
+}
+
+/// Sends the browser to the OIDC provider to log in.
+#[get("/api/oidc/login")]
+pub async fn oidc_login(req: HttpRequest, oidc: web::Data<Arc<Oidc>>, sessions: web::Data<Arc<Sessions>>) -> impl Responder {
+    if !oidc.is_enabled() {
+        warn!("OIDC login attempt from {} while OIDC is disabled", peer(&req));
+        return HttpResponse::NotFound().finish();
+    }
+    match oidc.authorization_url().await {
+        Ok((url, state)) => HttpResponse::Found()
+            .cookie(state_cookie(&sessions, state))
+            .insert_header((header::LOCATION, url))
+            .finish(),
+        Err(err) => {
+            error!("Failed to start OIDC login: {}", err);
+            HttpResponse::BadGateway().finish()
+        },
+    }
+}
+
+/// Where the OIDC provider sends the browser back to after logging in.
+#[get("/api/oidc/callback")]
+pub async fn oidc_callback(req: HttpRequest, oidc: web::Data<Arc<Oidc>>, sessions: web::Data<Arc<Sessions>>, audit: web::Data<Arc<AuditLog>>, callback: web::Query<Callback>) -> impl Responder {
+    let peer = peer(&req);
+    let Some(configuration) = oidc.configuration() else {
+        warn!("OIDC callback from {} while OIDC is disabled", peer);
+        return HttpResponse::NotFound().finish();
+    };
+    let Callback { code, state, error, error_description } = callback.into_inner();
+    let (code, state) = match (code, state, error) {
+        (Some(code), Some(state), None) => (code, state),
+        (_, _, error) => {
+            let reason = format!("{} {}", error.unwrap_or_else(|| "no code".to_string()), error_description.unwrap_or_default());
+            warn!("Failed OIDC login from {}: {}", peer, reason);
+            audit.record(AuditRecord::new(AuditEvent::LoginFailed, None, Some(peer)).with_detail(format!("oidc: {}", reason.trim())));
+            return HttpResponse::Unauthorized().cookie(state_removal_cookie(&sessions)).finish();
+        },
+    };
+
+    let state_cookie = req.cookie(oidc::STATE_COOKIE);
+    let user = match oidc.finish(&code, &state, state_cookie.as_ref().map(Cookie::value)).await {
+        Ok(user) => user,
+        Err(err) => {
+            warn!("Failed OIDC login from {}: {}", peer, err);
+            audit.record(AuditRecord::new(AuditEvent::LoginFailed, None, Some(peer)).with_detail(format!("oidc: {}", err)));
+            return HttpResponse::Unauthorized().cookie(state_removal_cookie(&sessions)).finish();
+        },
+    };
+    info!("User {} logged in with {} from {} with roles {:?}", user.name(), configuration.issuer(), peer, user.roles());
+    audit.record(AuditRecord::new(AuditEvent::Login, Some(user.name().to_string()), Some(peer)).with_detail(format!("oidc: {}", configuration.issuer())));
+    let id = sessions.create(user);
+    HttpResponse::Found()
+        .cookie(sessions.cookie(id))
+        .cookie(state_removal_cookie(&sessions))
+        .insert_header((header::LOCATION, configuration.post_login_url()))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::json;
+
+    use crate::server::{testing::{from_json, user, TestServer}, users::hash_password};
+
+    fn server() -> TestServer {
+        let users = json!({"alice": {"password": hash_password("correct horse").unwrap(), "roles": ["developers"]}});
+        TestServer { users: from_json(users), ..TestServer::default() }
+    }
+
+    #[actix_web::test]
+    async fn api_needs_a_login_session() {
+        let server = server();
+        let app = test::init_service(App::new().configure(server.configure())).await;
+        // The router decodes the path, so encoded ones have to be turned away as well
+        for path in ["/api/metrics", "/%61pi/metrics", "/%61pi/applications", "/%61%70%69/audit", "/api/nothing-here"] {
+            let res = test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+
+        let cookie = server.login(user("alice", &["developers"]));
+        for path in ["/api/metrics", "/%61pi/applications"] {
+            let res = test::call_service(&app, test::TestRequest::get().uri(path).cookie(cookie.clone()).to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn logging_in_opens_the_api() {
+        let server = server();
+        let app = test::init_service(App::new().configure(server.configure())).await;
+        let res = test::call_service(&app, test::TestRequest::get().uri("/api/session").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let login = |password: &str| test::TestRequest::post().uri("/api/login").set_json(json!({"username": "alice", "password": password})).to_request();
+        let res = test::call_service(&app, login("battery staple")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = test::call_service(&app, login("correct horse")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/api/applications").cookie(cookie).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn api_is_open_without_users() {
+        let server = TestServer::default();
+        let app = test::init_service(App::new().configure(server.configure())).await;
+        let res = test::call_service(&app, test::TestRequest::get().uri("/api/metrics").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -1,16 +1,21 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use actix_web::{body::MessageBody, get, web, HttpRequest, HttpResponse, Responder};
-use log::{debug, error, info};
-use futures::{future, stream::StreamExt};
+use actix_web::{body::MessageBody, get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::NaiveDateTime;
+use log::{debug, error, info, warn};
+use futures::{future, stream::{self, StreamExt}};
 use serde::Serialize;
-use tokio_stream::wrappers::BroadcastStream;
+use tokio::time::sleep;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 
-use crate::{message::Message, server::broadcaster::Broadcasters, Applicatiton};
+use crate::{message::{Message, SystemMessage, SystemMessages}, server::{audit::{AuditEvent, AuditLog, AuditQuery, AuditRecord}, broadcaster::{Broadcasters, SequencedMessage}, metrics::Metrics, oidc::Oidc, roles::Roles, sessions::CurrentUser, users::Users}, Applicatiton};
 
-#[get("/api/sse")]
-pub async fn data_outbound_sse(_req: HttpRequest, broadcasters: web::Data<Arc<Broadcasters>>, query: web::Query<HashMap<String, String>>,) -> impl Responder {
-    let application = match query.get("application") {
+/// How often SSE streams get a comment, so subscribers that went away are noticed and dropped even when nothing is logged.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[get("/sse")]
+pub async fn data_outbound_sse(req: HttpRequest, broadcasters: web::Data<Arc<Broadcasters>>, metrics: web::Data<Arc<Metrics>>, roles: web::Data<Arc<Roles>>, audit: web::Data<Arc<AuditLog>>, user: Option<web::ReqData<CurrentUser>>, query: web::Query<HashMap<String, String>>,) -> impl Responder {
+    let application: Applicatiton = match query.get("application") {
         Some(app_str) => {
             match serde_json::from_str(app_str) {
                 Ok(app) => app,
@@ -25,10 +30,26 @@ pub async fn data_outbound_sse(_req: HttpRequest, broadcasters: web::Data<Arc<Br
             return HttpResponse::BadRequest().finish();
         }
     };
+
+    if !roles.can_view(user.as_deref(), &application) {
+        warn!("User {} may not view application {}", user.as_ref().map(|user| user.name()).unwrap_or_default(), application.name());
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        Some(id) => match id.to_str().ok().and_then(|id| id.parse::<u64>().ok()) {
+            Some(id) => Some(id),
+            None => {
+                error!("Failed to parse Last-Event-ID header: {:?}", id);
+                return HttpResponse::BadRequest().finish();
+            }
+        },
+        None => None,
+    };
     
     let broadcasters = broadcasters.lock().await;
-    let rx = match broadcasters.get(&application) {
-        Some(tx) => tx.subscribe(),
+    let (replay, rx) = match broadcasters.get(&application) {
+        Some(broadcaster) => broadcaster.subscribe(last_event_id).await,
         None => {
             error!("No broadcaster found for application: {}", application.name());
             return HttpResponse::BadRequest().finish();
@@ -36,50 +57,45 @@ pub async fn data_outbound_sse(_req: HttpRequest, broadcasters: web::Data<Arc<Br
     };
     drop(broadcasters);
 
-    let stream = BroadcastStream::new(rx)
+    let gap = replay.gap.map(|(from, to)| {
+        info!("Events {} to {} of application {} are no longer available", from, to, application.name());
+        event(None, &Message::System(SystemMessage::new(application.clone(), SystemMessages::Gap { from, to }))).try_into_bytes()
+    });
+
+    let peer = req.peer_addr().map(|addr| addr.to_string());
+    let subscription = Metrics::subscribe(&metrics, &application, peer.clone());
+    let opened = AuditRecord::new(AuditEvent::StreamOpened, user.as_ref().map(|user| user.name().to_string()), peer).with_application(application.clone());
+    let tracked = AuditLog::track(&audit, opened, AuditEvent::StreamClosed);
+    let stream = stream::iter(replay.messages.into_iter().map(Ok))
+    .chain(BroadcastStream::new(rx))
     .take_while(|msg| future::ready(
-        match msg {
-            Ok(Message::ClientDisconnect) => false,
-            Err(_) => false,
-            _ => true,
-        }
+        !matches!(msg, Ok(SequencedMessage { message: Message::ClientDisconnect, .. }))
     ))
-    .map(|msg| {
+    .map(move |msg| {
+        // Kept with the stream, so it's recorded as closed once the stream is dropped
+        let _tracked = &tracked;
         match msg {
-            Ok(msg) => match msg {
-                Message::Data(data) => {
-                    debug!("Sending data message: {:#?}", data);
-                    let msg = match serde_json::to_string(&data) {
-                        Ok(msg) => msg,
-                        Err(err) => {
-                            error!("Failed to serialize data message: {}", err);
-                            return format!("data: Error: {}\n\n", err).try_into_bytes();
-                        },
-                    };
-                    format!("data: {}\n\n", msg).try_into_bytes()
-                }
-                Message::System(sys) => {
-                    debug!("Sending system message: {:#?}", sys);
-                    let msg = match serde_json::to_string(&sys) {
-                        Ok(msg) => msg,
-                        Err(err) => {
-                            error!("Failed to serialize system message: {}", err);
-                            return format!("data: Error: {}\n\n", err).try_into_bytes();
-                        },
-                    };
-                    format!("data: {}\n\n", msg).try_into_bytes()
-                }
-                Message::ClientDisconnect => {
-                    info!("Client disconnected");
-                    format!("data: Client disconnected\n\n").try_into_bytes()
-                }
+            Ok(msg) => {
+                subscription.sent();
+                event(Some(msg.sequence), &msg.message).try_into_bytes()
             },
-            Err(err) => {
-                error!("Error sending message: {:?}", err);
-                format!("data: Error: {}\n\n", err).try_into_bytes()
+            // A subscriber that can't keep up skips what it missed and carries on with the latest
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                warn!("Subscriber of application {} fell behind and skipped {} messages", application.name(), skipped);
+                subscription.lagged(skipped);
+                event(None, &Message::System(SystemMessage::new(application.clone(), SystemMessages::Lagged { skipped }))).try_into_bytes()
             },
         }
     });
+    let stream = stream::iter(gap).chain(stream);
+    // Ends along with the messages, the keep alive comments alone would go on forever
+    let keep_alive = stream::unfold((), |()| async {
+        sleep(KEEP_ALIVE_INTERVAL).await;
+        Some((Some(": keep-alive\n\n".to_string().try_into_bytes()), ()))
+    });
+    let stream = stream::select(stream.map(Some).chain(stream::once(future::ready(None))), keep_alive)
+        .take_while(|event| future::ready(event.is_some()))
+        .filter_map(future::ready);
 
     HttpResponse::Ok()
         .append_header(("content-type", "text/event-stream"))
@@ -88,22 +104,251 @@ pub async fn data_outbound_sse(_req: HttpRequest, broadcasters: web::Data<Arc<Br
         .streaming(stream)
 }
 
+fn event(sequence: Option<u64>, message: &Message) -> String {
+    let id = match sequence {
+        Some(sequence) => format!("id: {}\n", sequence),
+        None => String::new(),
+    };
+    match message {
+        Message::Data(data) => {
+            debug!("Sending data message: {:#?}", data);
+            match serde_json::to_string(data) {
+                Ok(msg) => format!("{}data: {}\n\n", id, msg),
+                Err(err) => {
+                    error!("Failed to serialize data message: {}", err);
+                    format!("data: Error: {}\n\n", err)
+                },
+            }
+        }
+        Message::System(sys) => {
+            debug!("Sending system message: {:#?}", sys);
+            match serde_json::to_string(sys) {
+                Ok(msg) => format!("{}data: {}\n\n", id, msg),
+                Err(err) => {
+                    error!("Failed to serialize system message: {}", err);
+                    format!("data: Error: {}\n\n", err)
+                },
+            }
+        }
+        Message::ClientDisconnect => {
+            info!("Client disconnected");
+            format!("{}data: Client disconnected\n\n", id)
+        }
+    }
+}
+
+/// Asks the clients of an application to send a file from their log directory, typically a rotated or gzip compressed one.
+/// The lines come through the application's SSE stream marked as historical, followed by a HistoryFinished system message.
+/// `from` and `to` are UTC timestamps like `2024-01-31T12:00:00` limiting the lines sent.
+#[post("/history")]
+pub async fn request_history(broadcasters: web::Data<Arc<Broadcasters>>, roles: web::Data<Arc<Roles>>, user: Option<web::ReqData<CurrentUser>>, query: web::Query<HashMap<String, String>>) -> impl Responder {
+    let application: Applicatiton = match query.get("application").map(|app_str| serde_json::from_str(app_str)) {
+        Some(Ok(app)) => app,
+        Some(Err(e)) => {
+            error!("Failed to parse application JSON: {}", e);
+            return HttpResponse::BadRequest().finish();
+        },
+        None => {
+            error!("No application parameter provided");
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    if !roles.can_view(user.as_deref(), &application) {
+        warn!("User {} may not view application {}", user.as_ref().map(|user| user.name()).unwrap_or_default(), application.name());
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let file = match query.get("file") {
+        Some(file) => file.clone(),
+        None => {
+            error!("No file parameter provided");
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let mut range = [None, None];
+    for (bound, name) in range.iter_mut().zip(["from", "to"]) {
+        if let Some(time) = query.get(name) {
+            match NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f") {
+                Ok(time) => *bound = time.and_utc().timestamp_nanos_opt(),
+                Err(e) => {
+                    error!("Failed to parse {} parameter {}: {}", name, time, e);
+                    return HttpResponse::BadRequest().finish();
+                }
+            }
+        }
+    }
+    let [from, to] = range;
+
+    let broadcasters = broadcasters.lock().await;
+    let sessions = match broadcasters.get(&application) {
+        Some(broadcaster) => broadcaster.sessions(),
+        None => {
+            error!("No broadcaster found for application: {}", application.name());
+            return HttpResponse::BadRequest().finish();
+        },
+    };
+    drop(broadcasters);
+
+    let request = Message::System(SystemMessage::new(application.clone(), SystemMessages::ReadHistory { file, from, to }));
+    let request = match serde_json::to_string(&request) {
+        Ok(msg) => msg,
+        Err(err) => {
+            error!("Failed to serialize read history message: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    for mut session in sessions {
+        if let Err(e) = session.text(request.clone()).await {
+            warn!("Failed to send read history message to a client of {}: {}", application.name(), e);
+        }
+    }
+    info!("Requested history of application {}", application.name());
+
+    HttpResponse::Accepted().finish()
+}
+
 #[derive(Serialize)]
 struct ApiResponse {
     message: String,
 }
 
-#[get("/api/hello")]
+#[get("/hello")]
 async fn hello() -> impl Responder {
     HttpResponse::Ok().json(ApiResponse {
         message: "Hello from Rust!".to_string(),
     })
 }
 
-#[get("/api/applications")]
-async fn current_registered_applications(broadcasters: web::Data<Arc<Broadcasters>>) -> impl Responder {
+#[get("/applications")]
+async fn current_registered_applications(broadcasters: web::Data<Arc<Broadcasters>>, roles: web::Data<Arc<Roles>>, user: Option<web::ReqData<CurrentUser>>) -> impl Responder {
     let broadcasters = broadcasters.lock().await;
-    let applications: Vec<Applicatiton> = broadcasters.keys().cloned().collect();
+    let applications: Vec<Applicatiton> = broadcasters.keys()
+        .filter(|application| roles.can_view(user.as_deref(), application))
+        .cloned()
+        .collect();
     drop(broadcasters);
     HttpResponse::Ok().json(applications)
-}
\ No newline at end of file
+}
+
+#[get("/metrics")]
+async fn current_metrics(metrics: web::Data<Arc<Metrics>>, roles: web::Data<Arc<Roles>>, user: Option<web::ReqData<CurrentUser>>) -> impl Responder {
+    HttpResponse::Ok().json(metrics.report(|application| roles.can_view(user.as_deref(), application)))
+}
+
+/// The latest audit records matching the query, for users with the admin role.
+#[get("/audit")]
+async fn query_audit_log(audit: web::Data<Arc<AuditLog>>, users: web::Data<Arc<Users>>, oidc: web::Data<Arc<Oidc>>, user: Option<web::ReqData<CurrentUser>>, query: web::Query<AuditQuery>) -> impl Responder {
+    if !audit.can_query(user.as_deref(), users.is_enabled() || oidc.is_enabled()) {
+        warn!("User {} may not query the audit log", user.as_ref().map(|user| user.name()).unwrap_or_default());
+        return HttpResponse::Forbidden().finish();
+    }
+    if !audit.is_enabled() {
+        error!("Audit log queried while AUDIT_LOG_FILE is not set");
+        return HttpResponse::NotFound().finish();
+    }
+    let audit = Arc::clone(&audit);
+    match web::block(move || audit.query(&query)).await {
+        Ok(Ok(records)) => HttpResponse::Ok().json(records),
+        Ok(Err(err)) => {
+            error!("Failed to read the audit log: {}", err);
+            HttpResponse::InternalServerError().finish()
+        },
+        Err(err) => {
+            error!("Failed to read the audit log: {}", err);
+            HttpResponse::InternalServerError().finish()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::{json, Value};
+
+    use std::sync::Arc;
+
+    use crate::{message::{DataMessage, Message}, server::{broadcaster::{self, BroadcasterConfiguration}, metrics::Metrics, testing::{application, from_json, user, TestServer}}};
+
+    /// Login, with roles for the applications of the billing and payments teams, and metrics of three applications.
+    fn server(login: bool) -> TestServer {
+        let roles = json!({"developers": ["*-api", "auth"], "payments": ["payments-*"]});
+        let users = match login {
+            true => from_json(json!({})),
+            false => TestServer::default().users,
+        };
+        let server = TestServer { users, roles: from_json(roles), ..TestServer::default() };
+        for name in ["billing-api", "auth", "payments-ledger"] {
+            server.metrics.received(&application(name), 100, None);
+        }
+        server
+    }
+
+    fn sse(name: &str) -> test::TestRequest {
+        test::TestRequest::get().uri(&format!("/api/sse?application=%7B%22SinglePod%22%3A%22{}%22%7D", name))
+    }
+
+    /// Applications of the metrics report, then those of its subscribers.
+    fn reported(report: &Value) -> (Vec<Value>, Vec<Value>) {
+        let applications = |list: &Value| list.as_array().unwrap().iter().map(|metrics| metrics["application"].clone()).collect();
+        (applications(&report["applications"]), applications(&report["subscribers"]))
+    }
+
+    #[actix_web::test]
+    async fn metrics_only_show_applications_the_user_may_view() {
+        let server = server(true);
+        let _subscription = Metrics::subscribe(&server.metrics, &application("payments-ledger"), Some("10.0.0.1:50000".to_string()));
+        let app = test::init_service(App::new().configure(server.configure())).await;
+
+        let cookie = server.login(user("alice", &["developers"]));
+        let report: Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/api/metrics").cookie(cookie).to_request()).await;
+        assert_eq!(reported(&report), (vec![json!(application("auth")), json!(application("billing-api"))], vec![]));
+
+        let cookie = server.login(user("bob", &["payments"]));
+        let report: Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/api/metrics").cookie(cookie).to_request()).await;
+        assert_eq!(reported(&report), (vec![json!(application("payments-ledger"))], vec![json!(application("payments-ledger"))]));
+    }
+
+    #[actix_web::test]
+    async fn streams_of_applications_the_user_may_not_view_are_forbidden() {
+        let server = server(true);
+        let app = test::init_service(App::new().configure(server.configure())).await;
+        let cookie = server.login(user("alice", &["developers"]));
+        let res = test::call_service(&app, sse("payments-ledger").cookie(cookie.clone()).to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // Allowed, but no client publishes it
+        let res = test::call_service(&app, sse("auth").cookie(cookie).to_request()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn nothing_is_visible_with_roles_but_no_login() {
+        let server = server(false);
+        let app = test::init_service(App::new().configure(server.configure())).await;
+        let report: Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/api/metrics").to_request()).await;
+        assert_eq!(reported(&report), (vec![], vec![]));
+        let res = test::call_service(&app, sse("auth").to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn subscribers_falling_behind_are_told_what_they_skipped() {
+        let broadcasters = Arc::new(broadcaster::new_broadcasters(BroadcasterConfiguration::new(10, 1024, 1)));
+        let server = TestServer { broadcasters, ..TestServer::default() };
+        let publisher = server.broadcasters.publish(&application("auth")).await;
+        let app = test::init_service(App::new().configure(server.configure())).await;
+
+        let res = test::call_service(&app, sse("auth").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        for row in ["first", "second", "third"] {
+            publisher.send(Message::Data(DataMessage::new(row.to_string(), application("auth"), false))).await;
+        }
+        publisher.send(Message::ClientDisconnect).await;
+
+        let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        let events: Vec<Value> = body.lines().filter_map(|line| line.strip_prefix("data: ")).map(|data| serde_json::from_str(data).unwrap()).collect();
+        assert_eq!(events, [json!({"type": "System", "application": application("auth"), "message": {"Lagged": {"skipped": 3}}, "timestamp": events[0]["timestamp"]})]);
+    }
+}
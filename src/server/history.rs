@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+use crate::message::Message;
+
+use super::broadcaster::SequencedMessage;
+
+/// Bounded backlog of the most recent data messages of an application, replayed to new subscribers.
+/// It also hands out the application's sequence numbers, as both need to move under the same lock.
+pub struct History {
+    messages: VecDeque<SequencedMessage>,
+    bytes: usize,
+    max_lines: usize,
+    max_bytes: usize,
+    first_sequence: u64,
+    last_sequence: u64,
+    last_evicted: Option<u64>
+}
+
+/// Replay for a subscriber resuming after a given event id.
+pub struct Replay {
+    /// Range of event ids that can no longer be replayed, if any.
+    pub gap: Option<(u64, u64)>,
+    pub messages: Vec<SequencedMessage>
+}
+
+impl History {
+    pub fn new(max_lines: usize, max_bytes: usize) -> Self {
+        // Seeding from the clock keeps ids increasing when an application re-registers after its last publisher left.
+        let first_sequence = chrono::Utc::now().timestamp_micros().max(1) as u64;
+        Self {
+            messages: VecDeque::new(),
+            bytes: 0,
+            max_lines,
+            max_bytes,
+            first_sequence,
+            last_sequence: first_sequence - 1,
+            last_evicted: None
+        }
+    }
+
+    /// Assigns the next sequence number to the message and keeps it if it is a live data message,
+    /// evicting the oldest ones until both limits hold again. Historical data was asked for by someone
+    /// and would only push live lines out, so it is never kept.
+    pub fn push(&mut self, message: Message) -> SequencedMessage {
+        self.last_sequence += 1;
+        let sequenced = SequencedMessage { sequence: self.last_sequence, message };
+
+        let size = match sequenced.message.data() {
+            Some(data) if !data.is_historical() => data.row().len(),
+            _ => return sequenced,
+        };
+        if self.max_lines == 0 || size > self.max_bytes {
+            self.last_evicted = Some(sequenced.sequence);
+            return sequenced;
+        }
+
+        self.messages.push_back(sequenced.clone());
+        self.bytes += size;
+
+        while self.messages.len() > self.max_lines || self.bytes > self.max_bytes {
+            match self.messages.pop_front() {
+                Some(evicted) => {
+                    self.bytes -= evicted.message.data().map(|data| data.row().len()).unwrap_or(0);
+                    self.last_evicted = Some(evicted.sequence);
+                },
+                None => break,
+            }
+        }
+
+        sequenced
+    }
+
+    pub fn snapshot(&self) -> Vec<SequencedMessage> {
+        self.messages.iter().cloned().collect()
+    }
+
+    /// Messages published after `last_event_id`, along with the ids that were already evicted.
+    pub fn since(&self, last_event_id: u64) -> Replay {
+        let first_available = match self.last_evicted {
+            Some(evicted) => evicted + 1,
+            None => self.first_sequence,
+        };
+
+        // The id comes from the client, one past the end must not overflow
+        let next = last_event_id.saturating_add(1);
+        if next < first_available {
+            return Replay { gap: Some((next, first_available)), messages: self.snapshot() };
+        }
+
+        let messages = self.messages.iter()
+            .filter(|message| message.sequence > last_event_id)
+            .cloned()
+            .collect();
+        Replay { gap: None, messages }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{message::{DataMessage, SystemMessage, SystemMessages}, server::testing::application};
+
+    fn data(row: &str) -> Message {
+        Message::Data(DataMessage::new(row.to_string(), application("payments"), false))
+    }
+
+    fn rows(messages: &[SequencedMessage]) -> Vec<&str> {
+        messages.iter().filter_map(|message| message.message.data()).map(DataMessage::row).collect()
+    }
+
+    #[test]
+    fn evicts_the_oldest_lines_over_the_line_limit() {
+        let mut history = History::new(2, 1024);
+        for row in ["one", "two", "three"] {
+            history.push(data(row));
+        }
+        assert_eq!(rows(&history.snapshot()), ["two", "three"]);
+    }
+
+    #[test]
+    fn evicts_the_oldest_lines_over_the_byte_limit() {
+        let mut history = History::new(10, 10);
+        for row in ["one", "two", "three"] {
+            history.push(data(row));
+        }
+        assert_eq!(rows(&history.snapshot()), ["two", "three"]);
+        history.push(data("longer than ten"));
+        assert_eq!(rows(&history.snapshot()), ["two", "three"]);
+        history.push(data("four"));
+        assert_eq!(rows(&history.snapshot()), ["three", "four"]);
+    }
+
+    #[test]
+    fn keeps_only_live_data() {
+        let mut history = History::new(10, 1024);
+        let system = history.push(Message::System(SystemMessage::new(application("payments"), SystemMessages::FileFound)));
+        let historical = history.push(Message::Data(DataMessage::new("old".to_string(), application("payments"), false).historical(None)));
+        let live = history.push(data("live"));
+        assert!(system.sequence < historical.sequence && historical.sequence < live.sequence);
+        assert_eq!(rows(&history.snapshot()), ["live"]);
+
+        let mut disabled = History::new(0, 1024);
+        disabled.push(data("live"));
+        assert!(disabled.snapshot().is_empty());
+    }
+
+    #[test]
+    fn replays_what_was_published_after_the_last_event_id() {
+        let mut history = History::new(10, 1024);
+        let first = history.push(data("one")).sequence;
+        history.push(Message::System(SystemMessage::new(application("payments"), SystemMessages::FileFound)));
+        history.push(data("two"));
+
+        let replay = history.since(first);
+        assert_eq!(replay.gap, None);
+        assert_eq!(rows(&replay.messages), ["two"]);
+        assert_eq!(rows(&history.since(first - 1).messages), ["one", "two"]);
+    }
+
+    #[test]
+    fn reports_evicted_ids_as_a_gap() {
+        let mut history = History::new(2, 1024);
+        let first = history.push(data("one")).sequence;
+        history.push(data("two"));
+        history.push(data("three"));
+
+        let replay = history.since(first - 1);
+        assert_eq!(replay.gap, Some((first, first + 1)));
+        assert_eq!(rows(&replay.messages), ["two", "three"]);
+    }
+
+    #[test]
+    fn replays_nothing_after_the_latest_or_an_unknown_id() {
+        let mut history = History::new(10, 1024);
+        let last = history.push(data("one")).sequence;
+        for last_event_id in [last, last + 1000, u64::MAX] {
+            let replay = history.since(last_event_id);
+            assert_eq!(replay.gap, None);
+            assert!(replay.messages.is_empty());
+        }
+    }
+}
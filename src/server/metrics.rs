@@ -0,0 +1,182 @@
+use std::{collections::BTreeMap, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use crate::Applicatiton;
+
+/// Counters of what the clients of each application sent and how the SSE subscribers keep up, served on `/api/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    applications: Mutex<BTreeMap<Applicatiton, ApplicationMetrics>>,
+    next_subscriber: AtomicU64,
+    subscribers: Mutex<BTreeMap<u64, Subscriber>>
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsReport {
+    applications: Vec<ApplicationMetricsReport>,
+    subscribers: Vec<SubscriberMetrics>
+}
+
+/// Counters of a connected SSE subscriber.
+#[derive(Debug, Serialize)]
+pub struct SubscriberMetrics {
+    id: u64,
+    application: Applicatiton,
+    peer: Option<String>,
+    connected_at: NaiveDateTime,
+    sent_messages: u64,
+    /// Times the subscriber fell too far behind and skipped messages
+    lag_events: u64,
+    skipped_messages: u64
+}
+
+struct Subscriber {
+    application: Applicatiton,
+    peer: Option<String>,
+    connected_at: NaiveDateTime,
+    counters: Arc<SubscriberCounters>
+}
+
+/// Counted by the subscriber's stream without taking any lock.
+#[derive(Default)]
+struct SubscriberCounters {
+    sent_messages: AtomicU64,
+    lag_events: AtomicU64,
+    skipped_messages: AtomicU64
+}
+
+/// Registration of an SSE subscriber in the metrics, removed again when dropped along with its stream.
+pub struct Subscription {
+    metrics: Arc<Metrics>,
+    id: u64,
+    counters: Arc<SubscriberCounters>
+}
+
+impl Subscription {
+    pub fn sent(&self) {
+        self.counters.sent_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn lagged(&self, skipped: u64) {
+        self.counters.lag_events.fetch_add(1, Ordering::Relaxed);
+        self.counters.skipped_messages.fetch_add(skipped, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.metrics.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&self.id);
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct ApplicationMetrics {
+    frames: u64,
+    compressed_frames: u64,
+    received_bytes: u64,
+    uncompressed_bytes: u64
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApplicationMetricsReport {
+    application: Applicatiton,
+    frames: u64,
+    compressed_frames: u64,
+    /// Bytes of the frames as received
+    received_bytes: u64,
+    /// Bytes the frames would have had without compression
+    uncompressed_bytes: u64,
+    /// `uncompressed_bytes` / `received_bytes`
+    compression_ratio: f64
+}
+
+impl Metrics {
+    /// Counts a frame received from a client, `uncompressed_bytes` being its size before compression if it was compressed.
+    pub fn received(&self, application: &Applicatiton, received_bytes: usize, uncompressed_bytes: Option<usize>) {
+        let mut applications = self.applications.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let metrics = applications.entry(application.clone()).or_default();
+        metrics.frames += 1;
+        metrics.received_bytes += received_bytes as u64;
+        metrics.uncompressed_bytes += uncompressed_bytes.unwrap_or(received_bytes) as u64;
+        if uncompressed_bytes.is_some() {
+            metrics.compressed_frames += 1;
+        }
+    }
+
+    pub fn subscribe(metrics: &Arc<Metrics>, application: &Applicatiton, peer: Option<String>) -> Subscription {
+        let id = metrics.next_subscriber.fetch_add(1, Ordering::Relaxed) + 1;
+        let counters = Arc::new(SubscriberCounters::default());
+        let subscriber = Subscriber { application: application.clone(), peer, connected_at: chrono::Utc::now().naive_utc(), counters: counters.clone() };
+        metrics.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(id, subscriber);
+        Subscription { metrics: metrics.clone(), id, counters }
+    }
+
+    /// Forgets the counters of an application once its last client is gone.
+    pub fn remove(&self, application: &Applicatiton) {
+        self.applications.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(application);
+    }
+
+    /// Counters of the applications `visible` lets through and of their subscribers.
+    pub fn report(&self, visible: impl Fn(&Applicatiton) -> bool) -> MetricsReport {
+        let subscribers = self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter()
+            .filter(|(_, subscriber)| visible(&subscriber.application))
+            .map(|(id, subscriber)| SubscriberMetrics {
+                id: *id,
+                application: subscriber.application.clone(),
+                peer: subscriber.peer.clone(),
+                connected_at: subscriber.connected_at,
+                sent_messages: subscriber.counters.sent_messages.load(Ordering::Relaxed),
+                lag_events: subscriber.counters.lag_events.load(Ordering::Relaxed),
+                skipped_messages: subscriber.counters.skipped_messages.load(Ordering::Relaxed),
+            })
+            .collect();
+        let applications = self.applications.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let applications = applications.iter().filter(|(application, _)| visible(application)).map(|(application, metrics)| ApplicationMetricsReport {
+            application: application.clone(),
+            frames: metrics.frames,
+            compressed_frames: metrics.compressed_frames,
+            received_bytes: metrics.received_bytes,
+            uncompressed_bytes: metrics.uncompressed_bytes,
+            compression_ratio: match metrics.received_bytes {
+                0 => 1.0,
+                received_bytes => metrics.uncompressed_bytes as f64 / received_bytes as f64,
+            },
+        }).collect();
+        MetricsReport { applications, subscribers }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::application;
+
+    #[test]
+    fn counts_what_subscribers_were_sent() {
+        let metrics = Arc::new(Metrics::default());
+        let subscription = Metrics::subscribe(&metrics, &application("auth"), None);
+        subscription.sent();
+        subscription.sent();
+        subscription.lagged(5);
+        let report = metrics.report(|_| true);
+        assert_eq!((report.subscribers[0].sent_messages, report.subscribers[0].lag_events, report.subscribers[0].skipped_messages), (2, 1, 5));
+
+        drop(subscription);
+        assert!(metrics.report(|_| true).subscribers.is_empty());
+    }
+
+    #[test]
+    fn forgets_removed_applications() {
+        let metrics = Metrics::default();
+        metrics.received(&application("auth"), 100, Some(400));
+        metrics.received(&application("billing"), 100, None);
+        assert_eq!(metrics.report(|_| true).applications[0].compression_ratio, 4.0);
+
+        metrics.remove(&application("auth"));
+        let report = metrics.report(|_| true);
+        assert_eq!(report.applications.len(), 1);
+        assert_eq!(report.applications[0].application, application("billing"));
+    }
+}
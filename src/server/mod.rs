@@ -1,5 +1,13 @@
+pub mod audit;
 pub mod broadcaster;
 pub mod controller;
-
-
-
+pub mod history;
+pub mod metrics;
+pub mod oidc;
+pub mod pattern;
+pub mod roles;
+pub mod sessions;
+#[cfg(test)]
+mod testing;
+pub mod tokens;
+pub mod users;
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// Application name pattern where `*` stands for any run of characters, e.g. `billing-*` or `*`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Pattern(String);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        Self(pattern.to_string())
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let mut parts = self.0.split('*');
+        // Without a `*` there's one part that has to be the whole name
+        let first = parts.next().unwrap_or_default();
+        let Some(mut rest) = name.strip_prefix(first) else {
+            return false;
+        };
+        let mut parts = parts.peekable();
+        if parts.peek().is_none() {
+            return rest.is_empty();
+        }
+        while let Some(part) = parts.next() {
+            if parts.peek().is_none() {
+                return rest.ends_with(part);
+            }
+            match rest.find(part) {
+                Some(index) => rest = &rest[index + part.len()..],
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_exact_names() {
+        assert!(Pattern::new("billing").matches("billing"));
+        assert!(!Pattern::new("billing").matches("billing-api"));
+        assert!(!Pattern::new("billing").matches("bill"));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(Pattern::new("*").matches(""));
+        assert!(Pattern::new("*").matches("anything"));
+        assert!(Pattern::new("billing-*").matches("billing-api"));
+        assert!(Pattern::new("billing-*").matches("billing-"));
+        assert!(!Pattern::new("billing-*").matches("billing"));
+        assert!(Pattern::new("*-api").matches("billing-api"));
+        assert!(!Pattern::new("*-api").matches("billing-api-2"));
+        assert!(Pattern::new("b*-*-2").matches("billing-api-2"));
+        assert!(!Pattern::new("a*a").matches("a"));
+        assert!(Pattern::new("a*a").matches("aa"));
+    }
+}
@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::{server::{pattern::Pattern, sessions::CurrentUser}, Applicatiton};
+
+/// Applications the users of each role may view in the UI, read from a JSON file like `{"payments": ["payments-*"], "admins": ["*"]}`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(from = "HashMap<String, Vec<Pattern>>")]
+pub struct Roles {
+    roles: Option<HashMap<String, Vec<Pattern>>>
+}
+
+impl From<HashMap<String, Vec<Pattern>>> for Roles {
+    fn from(roles: HashMap<String, Vec<Pattern>>) -> Self {
+        Self { roles: Some(roles) }
+    }
+}
+
+impl Roles {
+    pub fn is_enabled(&self) -> bool {
+        self.roles.is_some()
+    }
+
+    /// Whether `user` may view `application`. Anyone may without roles, with roles only users granted it may.
+    pub fn can_view(&self, user: Option<&CurrentUser>, application: &Applicatiton) -> bool {
+        let Some(roles) = &self.roles else {
+            return true;
+        };
+        let Some(user) = user else {
+            return false;
+        };
+        let name = application.name();
+        user.roles().iter()
+            .filter_map(|role| roles.get(role))
+            .flatten()
+            .any(|pattern| pattern.matches(&name))
+    }
+}
+
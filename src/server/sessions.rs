@@ -0,0 +1,132 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use actix_web::cookie::{time, Cookie, SameSite};
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+
+/// Cookie the id of a UI login session is kept in.
+pub const SESSION_COOKIE: &str = "webtail_session";
+
+/// Login sessions of UI users, kept in memory so restarting the server logs everyone out.
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Login>>,
+    ttl: Duration,
+    /// Only send the cookie over https
+    secure_cookie: bool
+}
+
+struct Login {
+    user: CurrentUser,
+    expires_at: DateTime<Utc>
+}
+
+/// User of the login session a request was made in, put in the request extensions once the session is checked.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    name: String,
+    /// Roles granting access to applications, looked up at login
+    roles: Vec<String>
+}
+
+impl CurrentUser {
+    pub fn new(name: String, roles: Vec<String>) -> Self {
+        Self { name, roles }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn roles(&self) -> &[String] {
+        &self.roles
+    }
+}
+
+/// Hex of 32 random bytes, for ids that must not be guessed.
+pub fn random_id() -> String {
+    let mut id = [0_u8; 32];
+    OsRng.fill_bytes(&mut id);
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration, secure_cookie: bool) -> Self {
+        Self { sessions: Mutex::new(HashMap::new()), ttl, secure_cookie }
+    }
+
+    /// Starts a session for `user`, returns its id.
+    pub fn create(&self, user: CurrentUser) -> String {
+        let id = random_id();
+        let now = Utc::now();
+        let mut sessions = self.sessions.lock().expect("sessions lock poisoned");
+        // Sessions nobody logged out of would pile up otherwise
+        sessions.retain(|_, login| login.expires_at > now);
+        sessions.insert(id.clone(), Login { user, expires_at: now + self.ttl });
+        id
+    }
+
+    /// The user logged in with session `id`, if it hasn't expired.
+    pub fn user(&self, id: &str) -> Option<CurrentUser> {
+        let mut sessions = self.sessions.lock().expect("sessions lock poisoned");
+        match sessions.get(id) {
+            Some(login) if login.expires_at > Utc::now() => Some(login.user.clone()),
+            Some(_) => {
+                sessions.remove(id);
+                None
+            },
+            None => None,
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.sessions.lock().expect("sessions lock poisoned").remove(id);
+    }
+
+    pub fn cookie(&self, id: String) -> Cookie<'static> {
+        let max_age = time::Duration::seconds(self.ttl.num_seconds());
+        self.cookie_builder(SESSION_COOKIE, id).max_age(max_age).finish()
+    }
+
+    /// Cookie telling the browser to forget the session.
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.cookie_builder(SESSION_COOKIE, String::new()).finish();
+        cookie.make_removal();
+        cookie
+    }
+
+    /// Cookie hidden from scripts and only sent with requests from other sites when following a link, over https if configured.
+    pub fn cookie_builder(&self, name: &'static str, value: String) -> actix_web::cookie::CookieBuilder<'static> {
+        Cookie::build(name, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(self.secure_cookie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::user;
+
+    #[test]
+    fn finds_the_user_of_a_session() {
+        let sessions = Sessions::new(Duration::hours(1), false);
+        let alice = sessions.create(user("alice", &[]));
+        let bob = sessions.create(user("bob", &[]));
+        assert_ne!(alice, bob);
+        assert_eq!(sessions.user(&alice).map(|user| user.name().to_string()), Some("alice".to_string()));
+        assert!(sessions.user("guessed").is_none());
+
+        sessions.remove(&alice);
+        assert!(sessions.user(&alice).is_none());
+        assert!(sessions.user(&bob).is_some());
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let sessions = Sessions::new(Duration::zero(), false);
+        let alice = sessions.create(user("alice", &[]));
+        assert!(sessions.user(&alice).is_none());
+    }
+}
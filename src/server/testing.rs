@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use actix_web::{cookie::Cookie, web};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{server::{audit::AuditLog, broadcaster::{self, BroadcasterConfiguration, Broadcasters}, controller, metrics::Metrics, oidc::Oidc, roles::Roles, sessions::{CurrentUser, Sessions}, tokens::TokenStore, users::Users}, Applicatiton};
+
+pub fn application(name: &str) -> Applicatiton {
+    Applicatiton::SinglePod(name.to_string())
+}
+
+pub fn user(name: &str, roles: &[&str]) -> CurrentUser {
+    CurrentUser::new(name.to_string(), roles.iter().map(|role| role.to_string()).collect())
+}
+
+/// Parses what would be read from a tokens, users or roles file.
+pub fn from_json<T: DeserializeOwned>(json: Value) -> Arc<T> {
+    Arc::new(serde_json::from_value(json).unwrap())
+}
+
+/// State of the server the route tests run against, tokens, login, roles and auditing are off unless set.
+#[derive(Clone)]
+pub struct TestServer {
+    pub broadcasters: Arc<Broadcasters>,
+    pub metrics: Arc<Metrics>,
+    pub tokens: Arc<TokenStore>,
+    pub users: Arc<Users>,
+    pub roles: Arc<Roles>,
+    pub audit: Arc<AuditLog>,
+    pub sessions: Arc<Sessions>
+}
+
+impl Default for TestServer {
+    fn default() -> Self {
+        Self {
+            broadcasters: Arc::new(broadcaster::new_broadcasters(BroadcasterConfiguration::new(10, 1024, 10))),
+            metrics: Arc::new(Metrics::default()),
+            tokens: Arc::new(TokenStore::default()),
+            users: Arc::new(Users::default()),
+            roles: Arc::new(Roles::default()),
+            audit: Arc::new(AuditLog::disabled("admin".to_string())),
+            sessions: Arc::new(Sessions::new(chrono::Duration::hours(1), false)),
+        }
+    }
+}
+
+impl TestServer {
+    /// Cookie of a new login session of `user`.
+    pub fn login(&self, user: CurrentUser) -> Cookie<'static> {
+        self.sessions.cookie(self.sessions.create(user))
+    }
+
+    /// The state and routes of the server, for `App::configure`.
+    pub fn configure(&self) -> impl FnOnce(&mut web::ServiceConfig) {
+        let broadcasters = web::Data::new(Arc::clone(&self.broadcasters));
+        let metrics = web::Data::new(Arc::clone(&self.metrics));
+        let tokens = web::Data::new(Arc::clone(&self.tokens));
+        let users = web::Data::new(Arc::clone(&self.users));
+        let roles = web::Data::new(Arc::clone(&self.roles));
+        let audit = web::Data::new(Arc::clone(&self.audit));
+        let sessions = web::Data::new(Arc::clone(&self.sessions));
+        move |cfg| {
+            cfg.app_data(broadcasters)
+                .app_data(metrics)
+                .app_data(tokens)
+                .app_data(users)
+                .app_data(roles)
+                .app_data(web::Data::new(Arc::new(Oidc::new(None))))
+                .app_data(audit)
+                .app_data(sessions)
+                .configure(controller::routes);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{collections::HashMap, fmt};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{server::pattern::Pattern, Applicatiton};
+
+/// Bearer tokens clients authenticate with on `/ws`, each allowed to publish the applications matching its patterns.
+/// Read from a JSON file like `{"token": ["billing-*", "auth"]}`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(from = "HashMap<String, Vec<Pattern>>")]
+pub struct TokenStore {
+    tokens: Option<HashMap<String, Vec<Pattern>>>
+}
+
+/// Why a client may not publish an application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// No bearer token in the `Authorization` header
+    MissingToken,
+    UnknownToken,
+    /// The token is known, but not for this application
+    NotAllowed
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::MissingToken => write!(f, "no bearer token"),
+            Rejection::UnknownToken => write!(f, "unknown token"),
+            Rejection::NotAllowed => write!(f, "token not allowed to publish the application"),
+        }
+    }
+}
+
+/// The token of an `Authorization: Bearer <token>` header value.
+fn bearer(authorization: Option<&str>) -> Option<&str> {
+    authorization?.trim().strip_prefix("Bearer ").map(str::trim)
+}
+
+impl From<HashMap<String, Vec<Pattern>>> for TokenStore {
+    fn from(tokens: HashMap<String, Vec<Pattern>>) -> Self {
+        Self { tokens: Some(tokens) }
+    }
+}
+
+impl TokenStore {
+    pub fn is_enabled(&self) -> bool {
+        self.tokens.is_some()
+    }
+
+    /// Identifies the token in the `Authorization` header without giving it away, for the audit log.
+    pub fn fingerprint(&self, authorization: Option<&str>) -> Option<String> {
+        self.tokens.as_ref()?;
+        let token = bearer(authorization)?;
+        let digest = Sha256::digest(token.as_bytes());
+        Some(format!("token:{}", digest.iter().take(4).map(|byte| format!("{:02x}", byte)).collect::<String>()))
+    }
+
+    /// Checks the value of the `Authorization` header of a client publishing `application`.
+    pub fn authorize(&self, authorization: Option<&str>, application: &Applicatiton) -> Result<(), Rejection> {
+        let Some(tokens) = &self.tokens else {
+            return Ok(());
+        };
+        let token = bearer(authorization).ok_or(Rejection::MissingToken)?;
+        let patterns = tokens.get(token).ok_or(Rejection::UnknownToken)?;
+        match patterns.iter().any(|pattern| pattern.matches(&application.name())) {
+            true => Ok(()),
+            false => Err(Rejection::NotAllowed),
+        }
+    }
+}
+
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use argon2::{password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString}, Argon2};
+use rand::rngs::OsRng;
+use serde::Deserialize;
+
+/// Users who may log in to the UI, read from a JSON file like `{"alice": {"password": "$argon2id$v=19$...", "roles": ["developers"]}}`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "HashMap<String, User>")]
+pub struct Users {
+    users: Option<HashMap<String, User>>,
+    /// Checked against for unknown users, so they take as long to turn away as wrong passwords
+    dummy: String
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    /// Argon2 hash in the PHC string format, as printed by `server hash-password`
+    password: String,
+    /// Roles granting access to applications, see `Roles`
+    #[serde(default)]
+    roles: Vec<String>
+}
+
+/// Hashes a password for the users file.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Error hashing password: {}", e))
+}
+
+impl TryFrom<HashMap<String, User>> for Users {
+    type Error = String;
+
+    fn try_from(users: HashMap<String, User>) -> Result<Self, String> {
+        for (name, user) in &users {
+            PasswordHash::new(&user.password).map_err(|e| format!("invalid password hash of user {}: {}", name, e))?;
+        }
+        Ok(Self { users: Some(users), dummy: hash_password("")? })
+    }
+}
+
+impl Users {
+    pub fn is_enabled(&self) -> bool {
+        self.users.is_some()
+    }
+
+    pub fn roles(&self, name: &str) -> Vec<String> {
+        self.users.as_ref().and_then(|users| users.get(name)).map(|user| user.roles.clone()).unwrap_or_default()
+    }
+
+    /// Whether `password` is the password of user `name`. Takes long on purpose, better not called on the async runtime.
+    pub fn verify(&self, name: &str, password: &str) -> bool {
+        let Some(users) = &self.users else {
+            return false;
+        };
+        let (hash, known) = match users.get(name) {
+            Some(user) => (&user.password, true),
+            None => (&self.dummy, false),
+        };
+        let verified = PasswordHash::new(hash)
+            .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+            .unwrap_or(false);
+        verified && known
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users(name: &str, password: &str) -> Users {
+        let user = User { password: hash_password(password).unwrap(), roles: vec![] };
+        Users { users: Some(HashMap::from([(name.to_string(), user)])), dummy: hash_password("").unwrap() }
+    }
+
+    #[test]
+    fn verifies_passwords() {
+        let users = users("alice", "correct horse");
+        assert!(users.verify("alice", "correct horse"));
+        assert!(!users.verify("alice", "battery staple"));
+        assert!(!users.verify("bob", "correct horse"));
+        assert!(!users.verify("bob", ""));
+    }
+
+    #[test]
+    fn nobody_logs_in_without_users() {
+        assert!(!Users::default().verify("alice", ""));
+    }
+}